@@ -1,15 +1,59 @@
 mod modules;
 use modules::vm::Vm;
-use modules::utils::{ center_print, debug, parse_args };
-use modules::parser::parse_program;
+use modules::keyboard::Keyboard;
+use modules::utils::{ center_print, debug, parse_args, Args, Command, USAGE };
+use modules::parser::{ assemble_object, parse_program };
+use modules::listing::listing;
+use modules::disasm::disassemble;
+use modules::container::{ is_binary, read_program, write_program };
+use modules::program::Program;
+use modules::object::{ is_object, read_object, write_object };
+use modules::linker::link;
+use modules::fmt::format;
+use modules::error::report;
+use modules::lexer::SourceFile;
+use modules::lint::{ lint, Severity };
+use modules::cfg::dot;
+use modules::lsp::serve;
 
 fn main() {
-    let args = parse_args();
-    let file_path = args.get(1).map(|s| s.as_str());
-    let debug_f = args.get(2).map(|s| s.as_str());
-    let (program, debug_mode) = parse_program(file_path);
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        eprint!("{}", USAGE);
+        std::process::exit(1);
+    });
+    match args.command {
+        Command::Run => run(&args),
+        Command::Asm => assemble(&args),
+        Command::Disasm => disasm(&args),
+        Command::Link => link_objects(&args),
+        Command::Fmt => fmt(&args),
+        Command::Lint => lint_program(&args),
+        Command::Cfg => cfg(&args),
+        Command::Lsp => serve(&args.include_dirs, &args.defines, args.key_matrix),
+    }
+}
+
+fn run(args: &Args) {
+    let (program, debug_mode) = load(args);
     let mut vm = Vm::new();
-    vm.load_program(program);
+    vm.keyboard = Keyboard::new(args.key_queue, args.key_overflow);
+    if args.key_matrix {
+        vm.keyboard.enable_matrix(std::time::Duration::from_millis(args.key_hold));
+    }
+    vm.set_fps(args.fps);
+    vm.source = program.source;
+    let loaded = if args.von_neumann {
+        vm.load_program_into_memory(&program.instructions, args.load_addr)
+    } else {
+        vm.load_program(program.instructions);
+        Ok(())
+    };
+    if let Err(e) = loaded.and_then(|()| vm.load_data(&program.data)) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+    vm.start_at(program.entry);
 
     center_print("OUTPUT", 80);
 
@@ -17,7 +61,168 @@ fn main() {
     vm.run();
     let elapsed_time = start_time.elapsed();
     center_print("EXECUTION FINISHED", 80);
-    if debug_f == Some("-d") || debug_mode {
+    if args.debug || debug_mode {
         debug(elapsed_time, &vm);
     }
 }
+
+// `asm`: assembles the file into a binary (FILE.e8b unless `-o` says
+// otherwise), or with `-c` an object file (FILE.e8o), and if asked a listing.
+fn assemble(args: &Args) {
+    let file = args.file.as_deref().unwrap();
+    let (program, written) = if args.object {
+        let object = assemble_object(file, &args.include_dirs, &args.defines);
+        let written = write_object(&object);
+        (object.program, written)
+    } else {
+        let (program, _) = parse_program(Some(file), &args.include_dirs, &args.defines);
+        let written = write_program(&program);
+        (program, written)
+    };
+    let extension = if args.object { "e8o" } else { "e8b" };
+    let output = args.output.clone()
+        .unwrap_or_else(|| std::path::Path::new(file).with_extension(extension).display().to_string());
+    let bytes = written.unwrap_or_else(|e| {
+        eprintln!("error: {}: {}", file, e);
+        std::process::exit(1);
+    });
+    std::fs::write(&output, bytes).unwrap_or_else(|e| panic!("Cannot write {}: {}", output, e));
+    if let Some(path) = &args.listing {
+        std::fs::write(path, listing(&program, file))
+            .unwrap_or_else(|e| panic!("Cannot write {}: {}", path, e));
+    }
+}
+
+// `link`: combines object files into a binary, named after the first one
+// unless `-o` says otherwise.
+fn link_objects(args: &Args) {
+    let objects: Vec<_> = args.inputs.iter().map(|path| {
+        let bytes = std::fs::read(path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path, e));
+        let object = read_object(&bytes).unwrap_or_else(|e| {
+            eprintln!("error: {}: {}", path, e);
+            std::process::exit(1);
+        });
+        (path.clone(), object)
+    }).collect();
+    let program = link(&objects).unwrap_or_else(|errors| {
+        for error in errors {
+            eprintln!("error: {}", error);
+        }
+        std::process::exit(1);
+    });
+    let output = args.output.clone()
+        .unwrap_or_else(|| std::path::Path::new(&args.inputs[0]).with_extension("e8b").display().to_string());
+    let bytes = write_program(&program).unwrap_or_else(|e| {
+        eprintln!("error: {}: {}", output, e);
+        std::process::exit(1);
+    });
+    std::fs::write(&output, bytes).unwrap_or_else(|e| panic!("Cannot write {}: {}", output, e));
+}
+
+// `fmt`: rewrites the files in the standard style; with `--check` only lists
+// those that would change, and fails if there are any.
+fn fmt(args: &Args) {
+    let mut failed = false;
+    for path in &args.inputs {
+        let text = std::fs::read_to_string(path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path, e));
+        let formatted = match format(&text) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                let file = SourceFile { path: path.into(), text, included_from: None };
+                report(&[file], &e);
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == text {
+            continue;
+        }
+        if args.check {
+            println!("{} is not formatted", path);
+            failed = true;
+        } else {
+            std::fs::write(path, formatted).unwrap_or_else(|e| panic!("Cannot write {}: {}", path, e));
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+// `lint`: prints what static analysis finds in a source file or binary, and
+// fails if any of it is an error.
+fn lint_program(args: &Args) {
+    let (program, _) = load(args);
+    let diagnostics = lint(&program, args.key_matrix);
+    for diagnostic in &diagnostics {
+        match program.source.location(diagnostic.index) {
+            Some(location) => {
+                println!("{}: {}:{}: {}", diagnostic.severity, location.file, location.line, diagnostic.message);
+                println!("{} | {}", location.line, location.text);
+                if let Some((name, call)) = &location.expanded_at {
+                    println!("note: in macro {} called at {}", name, call);
+                }
+            }
+            None => println!("{}: {}: {}", diagnostic.severity, program.source.describe(diagnostic.index), diagnostic.message),
+        }
+    }
+    let errors = diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error).count();
+    let warnings = diagnostics.len() - errors;
+    if !diagnostics.is_empty() {
+        println!("{} error(s), {} warning(s)", errors, warnings);
+    }
+    if errors > 0 {
+        std::process::exit(1);
+    }
+}
+
+// `disasm`: prints a binary, or the assembled form of a source file, as
+// source; to the `-o` file if given.
+fn disasm(args: &Args) {
+    let (program, _) = load(args);
+    let text = disassemble(&program);
+    match &args.output {
+        Some(path) => std::fs::write(path, text).unwrap_or_else(|e| panic!("Cannot write {}: {}", path, e)),
+        None => print!("{}", text),
+    }
+}
+
+// `cfg`: prints the control-flow graph of a source file or binary in DOT;
+// to the `-o` file if given.
+fn cfg(args: &Args) {
+    let (program, _) = load(args);
+    let text = dot(&program, args.file.as_deref().unwrap());
+    match &args.output {
+        Some(path) => std::fs::write(path, text).unwrap_or_else(|e| panic!("Cannot write {}: {}", path, e)),
+        None => print!("{}", text),
+    }
+}
+
+// The program in the file given, binary or source; IDLE mode without one.
+fn load(args: &Args) -> (Program, bool) {
+    let magic = args.file.as_deref().map(file_magic).unwrap_or_default();
+    match args.file.as_deref() {
+        Some(path) if is_binary(&magic) => (load_binary(path), false),
+        Some(path) if is_object(&magic) => {
+            eprintln!("error: {} is an object file, link it into a program first", path);
+            std::process::exit(1);
+        }
+        file => parse_program(file, &args.include_dirs, &args.defines),
+    }
+}
+
+// The first four bytes of the file, which tell binaries and object files
+// from source.
+fn file_magic(path: &str) -> [u8; 4] {
+    let mut magic = [0; 4];
+    let _ = std::fs::File::open(path).and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic));
+    magic
+}
+
+fn load_binary(path: &str) -> Program {
+    let bytes = std::fs::read(path).expect("Failed to read file");
+    read_program(&bytes).unwrap_or_else(|e| {
+        eprintln!("error: {}: {}", path, e);
+        std::process::exit(1);
+    })
+}
@@ -0,0 +1,261 @@
+use crate::modules::vm::{ Instruction, Reg, Source, MemSrc };
+
+// Byte encoding of instructions, used when the program lives in `Vm::mem`.
+//
+// Every instruction starts with a one-byte opcode. Operands follow:
+// a register is one byte (A=0 .. E=4), a source or memory source is a kind
+// byte (0 = register, 1 = literal, 2 = [address], 3 = [register]) followed by
+// its value, jump targets and SLP durations are 16-bit little endian and the
+// PRINT/PRINTCH newline flag is one byte. Opcode 0 is HALT, so running into
+// zeroed memory stops the program.
+
+// Bumped whenever the encoding changes, so old binaries are not misread.
+pub const ISA_VERSION: u8 = 1;
+
+const HALT: u8 = 0x00;
+const MOV: u8 = 0x01;
+const STORE: u8 = 0x02;
+const ADD: u8 = 0x03;
+const SUB: u8 = 0x04;
+const MUL: u8 = 0x05;
+const MULH: u8 = 0x06;
+const DIV: u8 = 0x07;
+const MOD: u8 = 0x08;
+const JMP: u8 = 0x09;
+const JZ: u8 = 0x0A;
+const JNZ: u8 = 0x0B;
+const LOOP: u8 = 0x0C;
+const PRINT: u8 = 0x0D;
+const PRINTCH: u8 = 0x0E;
+const INPUT: u8 = 0x0F;
+const INKEY: u8 = 0x10;
+const KEYMOD: u8 = 0x11;
+const KEYCOUNT: u8 = 0x12;
+const DRAW: u8 = 0x13;
+const SLP: u8 = 0x14;
+const CMP: u8 = 0x15;
+const RAND: u8 = 0x16;
+const CLS: u8 = 0x17;
+const CTS: u8 = 0x18;
+const RENDER: u8 = 0x19;
+const FRAME: u8 = 0x1A;
+const FPS: u8 = 0x1B;
+const FCOUNT: u8 = 0x1C;
+
+const KIND_REG: u8 = 0;
+const KIND_LIT: u8 = 1;
+const KIND_ADDR: u8 = 2;
+const KIND_MEM_REG: u8 = 3;
+
+pub fn size(instruction: &Instruction) -> usize {
+    match instruction {
+        Instruction::MOV(..) | Instruction::STORE(..) | Instruction::ADD(..) | Instruction::SUB(..)
+        | Instruction::MUL(..) | Instruction::DIV(..) | Instruction::MOD(..) | Instruction::CMP(..) => 4,
+        Instruction::MULH(..) | Instruction::JMP(_) | Instruction::JZ(_) | Instruction::JNZ(_)
+        | Instruction::PRINT(..) | Instruction::PRINTCH(..) | Instruction::SLP(_) => 3,
+        Instruction::LOOP(..) => 4,
+        Instruction::DRAW(..) => 7,
+        Instruction::INPUT(_) | Instruction::INKEY(_) | Instruction::KEYMOD(_) | Instruction::KEYCOUNT(_)
+        | Instruction::RAND(_) | Instruction::FPS(_) | Instruction::FCOUNT(_) => 2,
+        Instruction::CLS | Instruction::CTS | Instruction::RENDER | Instruction::FRAME | Instruction::HALT => 1,
+    }
+}
+
+// Encodes one instruction as is; jump targets are written unchanged.
+pub fn encode_instruction(instruction: &Instruction, out: &mut Vec<u8>) -> Result<(), String> {
+    match *instruction {
+        Instruction::MOV(reg, src) => { out.push(MOV); push_reg(out, reg); push_source(out, src); }
+        Instruction::STORE(reg, mem) => { out.push(STORE); push_reg(out, reg); push_mem(out, mem); }
+        Instruction::ADD(reg, src) => { out.push(ADD); push_reg(out, reg); push_source(out, src); }
+        Instruction::SUB(reg, src) => { out.push(SUB); push_reg(out, reg); push_source(out, src); }
+        Instruction::MUL(reg, src) => { out.push(MUL); push_reg(out, reg); push_source(out, src); }
+        Instruction::MULH(dest, src1, src2) => {
+            out.push(MULH);
+            push_reg(out, dest);
+            push_reg(out, src1);
+            push_reg(out, src2);
+        }
+        Instruction::DIV(reg, src) => { out.push(DIV); push_reg(out, reg); push_source(out, src); }
+        Instruction::MOD(reg, src) => { out.push(MOD); push_reg(out, reg); push_source(out, src); }
+        Instruction::JMP(addr) => { out.push(JMP); push_u16(out, addr, "Jump target")?; }
+        Instruction::JZ(addr) => { out.push(JZ); push_u16(out, addr, "Jump target")?; }
+        Instruction::JNZ(addr) => { out.push(JNZ); push_u16(out, addr, "Jump target")?; }
+        Instruction::LOOP(addr, reg) => { out.push(LOOP); push_u16(out, addr, "Jump target")?; push_reg(out, reg); }
+        Instruction::PRINT(reg, newline) => { out.push(PRINT); push_reg(out, reg); out.push(newline as u8); }
+        Instruction::PRINTCH(reg, newline) => { out.push(PRINTCH); push_reg(out, reg); out.push(newline as u8); }
+        Instruction::INPUT(reg) => { out.push(INPUT); push_reg(out, reg); }
+        Instruction::INKEY(reg) => { out.push(INKEY); push_reg(out, reg); }
+        Instruction::KEYMOD(reg) => { out.push(KEYMOD); push_reg(out, reg); }
+        Instruction::KEYCOUNT(reg) => { out.push(KEYCOUNT); push_reg(out, reg); }
+        Instruction::DRAW(x, y, src) => {
+            out.push(DRAW);
+            push_source(out, x);
+            push_source(out, y);
+            push_source(out, src);
+        }
+        Instruction::SLP(duration) => { out.push(SLP); push_u16(out, duration, "SLP duration")?; }
+        Instruction::CMP(reg, src) => { out.push(CMP); push_reg(out, reg); push_source(out, src); }
+        Instruction::RAND(reg) => { out.push(RAND); push_reg(out, reg); }
+        Instruction::CLS => out.push(CLS),
+        Instruction::CTS => out.push(CTS),
+        Instruction::RENDER => out.push(RENDER),
+        Instruction::FRAME => out.push(FRAME),
+        Instruction::FPS(rate) => { out.push(FPS); out.push(rate); }
+        Instruction::FCOUNT(reg) => { out.push(FCOUNT); push_reg(out, reg); }
+        Instruction::HALT => out.push(HALT),
+    }
+    Ok(())
+}
+
+// Encodes a program to be loaded at `origin`, turning jump targets from
+// instruction indices into byte addresses.
+pub fn encode_program(program: &[Instruction], origin: usize) -> Result<Vec<u8>, String> {
+    let addresses = instruction_addresses(program, origin);
+    let mut out = Vec::with_capacity(addresses[program.len()] - origin);
+    for (index, instruction) in program.iter().enumerate() {
+        let instruction = match instruction.jump_target() {
+            Some(target) => {
+                let addr = *addresses.get(target).ok_or_else(|| {
+                    format!("Instruction {} jumps to {}, past the end of the program", index, target)
+                })?;
+                instruction.with_jump_target(addr)
+            }
+            None => *instruction,
+        };
+        encode_instruction(&instruction, &mut out)?;
+    }
+    Ok(out)
+}
+
+// The address of each instruction once encoded at `origin`, followed by the
+// address just past the program.
+pub fn instruction_addresses(program: &[Instruction], origin: usize) -> Vec<usize> {
+    let mut addresses = Vec::with_capacity(program.len() + 1);
+    let mut addr = origin;
+    for instruction in program {
+        addresses.push(addr);
+        addr += size(instruction);
+    }
+    addresses.push(addr);
+    addresses
+}
+
+// Decodes the instruction at the start of `bytes`, returning it and its size.
+pub fn decode_instruction(bytes: &[u8]) -> Result<(Instruction, usize), String> {
+    let opcode = *bytes.first().ok_or("Unexpected end of memory")?;
+    let mut reader = Reader { bytes, pos: 1 };
+    let instruction = match opcode {
+        HALT => Instruction::HALT,
+        MOV => Instruction::MOV(reader.reg()?, reader.source()?),
+        STORE => Instruction::STORE(reader.reg()?, reader.mem()?),
+        ADD => Instruction::ADD(reader.reg()?, reader.source()?),
+        SUB => Instruction::SUB(reader.reg()?, reader.source()?),
+        MUL => Instruction::MUL(reader.reg()?, reader.source()?),
+        MULH => Instruction::MULH(reader.reg()?, reader.reg()?, reader.reg()?),
+        DIV => Instruction::DIV(reader.reg()?, reader.source()?),
+        MOD => Instruction::MOD(reader.reg()?, reader.source()?),
+        JMP => Instruction::JMP(reader.u16()?),
+        JZ => Instruction::JZ(reader.u16()?),
+        JNZ => Instruction::JNZ(reader.u16()?),
+        LOOP => Instruction::LOOP(reader.u16()?, reader.reg()?),
+        PRINT => Instruction::PRINT(reader.reg()?, reader.byte()? != 0),
+        PRINTCH => Instruction::PRINTCH(reader.reg()?, reader.byte()? != 0),
+        INPUT => Instruction::INPUT(reader.reg()?),
+        INKEY => Instruction::INKEY(reader.reg()?),
+        KEYMOD => Instruction::KEYMOD(reader.reg()?),
+        KEYCOUNT => Instruction::KEYCOUNT(reader.reg()?),
+        DRAW => Instruction::DRAW(reader.source()?, reader.source()?, reader.source()?),
+        SLP => Instruction::SLP(reader.u16()?),
+        CMP => Instruction::CMP(reader.reg()?, reader.source()?),
+        RAND => Instruction::RAND(reader.reg()?),
+        CLS => Instruction::CLS,
+        CTS => Instruction::CTS,
+        RENDER => Instruction::RENDER,
+        FRAME => Instruction::FRAME,
+        FPS => Instruction::FPS(reader.byte()?),
+        FCOUNT => Instruction::FCOUNT(reader.reg()?),
+        _ => return Err(format!("Invalid opcode: {:#04x}", opcode)),
+    };
+    Ok((instruction, reader.pos))
+}
+
+fn reg_code(reg: Reg) -> u8 {
+    match reg {
+        Reg::A => 0,
+        Reg::B => 1,
+        Reg::C => 2,
+        Reg::D => 3,
+        Reg::E => 4,
+    }
+}
+
+fn push_reg(out: &mut Vec<u8>, reg: Reg) {
+    out.push(reg_code(reg));
+}
+
+fn push_source(out: &mut Vec<u8>, src: Source) {
+    match src {
+        Source::Reg(reg) => out.extend([KIND_REG, reg_code(reg)]),
+        Source::Lit(value) => out.extend([KIND_LIT, value]),
+        Source::Mem(mem) => push_mem(out, mem),
+    }
+}
+
+fn push_mem(out: &mut Vec<u8>, mem: MemSrc) {
+    match mem {
+        MemSrc::Addr(addr) => out.extend([KIND_ADDR, addr]),
+        MemSrc::Reg(reg) => out.extend([KIND_MEM_REG, reg_code(reg)]),
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, value: usize, what: &str) -> Result<(), String> {
+    let value = u16::try_from(value).map_err(|_| format!("{} {} does not fit in 16 bits", what, value))?;
+    out.extend(value.to_le_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.pos).ok_or("Unexpected end of memory")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn u16(&mut self) -> Result<usize, String> {
+        Ok(u16::from_le_bytes([self.byte()?, self.byte()?]) as usize)
+    }
+
+    fn reg(&mut self) -> Result<Reg, String> {
+        match self.byte()? {
+            0 => Ok(Reg::A),
+            1 => Ok(Reg::B),
+            2 => Ok(Reg::C),
+            3 => Ok(Reg::D),
+            4 => Ok(Reg::E),
+            code => Err(format!("Invalid register code: {}", code)),
+        }
+    }
+
+    fn source(&mut self) -> Result<Source, String> {
+        match self.byte()? {
+            KIND_REG => Ok(Source::Reg(self.reg()?)),
+            KIND_LIT => Ok(Source::Lit(self.byte()?)),
+            KIND_ADDR => Ok(Source::Mem(MemSrc::Addr(self.byte()?))),
+            KIND_MEM_REG => Ok(Source::Mem(MemSrc::Reg(self.reg()?))),
+            kind => Err(format!("Invalid operand kind: {}", kind)),
+        }
+    }
+
+    fn mem(&mut self) -> Result<MemSrc, String> {
+        match self.byte()? {
+            KIND_ADDR => Ok(MemSrc::Addr(self.byte()?)),
+            KIND_MEM_REG => Ok(MemSrc::Reg(self.reg()?)),
+            kind => Err(format!("Invalid memory operand kind: {}", kind)),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::{ BTreeSet, HashMap };
+use std::fmt::Write;
+use crate::modules::disasm::{ labels, text };
+use crate::modules::program::{ Program, SymbolKind };
+use crate::modules::vm::Instruction;
+
+// A run of instructions that is only entered at its first and left after its
+// last: it starts at a label, a jump target or after a jump or HALT.
+pub struct BasicBlock {
+    pub start: usize,
+    // one past the last instruction
+    pub end: usize,
+    pub successors: Vec<(Successor, Edge)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Successor {
+    // by index in the block list
+    Block(usize),
+    // HALT, running past the last instruction, or a jump past the end
+    Exit,
+}
+
+// Why control goes along an edge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    // falls through to the next block, or JMP
+    Always,
+    Zero,
+    NotZero,
+}
+
+pub fn basic_blocks(program: &Program) -> Vec<BasicBlock> {
+    let code = &program.instructions;
+    let mut leaders = BTreeSet::from([0, program.entry]);
+    for symbol in &program.source.symbols {
+        if symbol.kind == SymbolKind::Label {
+            leaders.insert(symbol.value as usize);
+        }
+    }
+    for (index, instruction) in code.iter().enumerate() {
+        if let Some(target) = instruction.jump_target() {
+            leaders.insert(target);
+        }
+        if instruction.jump_target().is_some() || matches!(instruction, Instruction::HALT) {
+            leaders.insert(index + 1);
+        }
+    }
+    let starts: Vec<usize> = leaders.into_iter().filter(|&start| start < code.len()).collect();
+    let block_at: HashMap<usize, usize> = starts.iter().enumerate().map(|(block, &start)| (start, block)).collect();
+    let successor = |index: usize| block_at.get(&index).map_or(Successor::Exit, |&block| Successor::Block(block));
+
+    starts.iter().enumerate().map(|(block, &start)| {
+        let end = starts.get(block + 1).copied().unwrap_or(code.len());
+        let successors = match code[end - 1] {
+            Instruction::HALT => vec![(Successor::Exit, Edge::Always)],
+            Instruction::JMP(target) => vec![(successor(target), Edge::Always)],
+            Instruction::JZ(target) => vec![(successor(target), Edge::Zero), (successor(end), Edge::NotZero)],
+            Instruction::JNZ(target) | Instruction::LOOP(target, _) => {
+                vec![(successor(target), Edge::NotZero), (successor(end), Edge::Zero)]
+            }
+            _ => vec![(successor(end), Edge::Always)],
+        };
+        BasicBlock { start, end, successors }
+    }).collect()
+}
+
+// The control-flow graph in Graphviz DOT, one box per basic block listing its
+// instructions and the source lines they came from. Blocks that cannot be
+// reached from the entry point are drawn dashed.
+pub fn dot(program: &Program, title: &str) -> String {
+    let blocks = basic_blocks(program);
+    let labels = labels(&program.instructions, &program.source.symbols);
+    let mut reachable = vec![false; blocks.len()];
+    let mut pending: Vec<usize> = blocks.iter().position(|block| block.start == program.entry).into_iter().collect();
+    while let Some(block) = pending.pop() {
+        if !std::mem::replace(&mut reachable[block], true) {
+            pending.extend(blocks[block].successors.iter().filter_map(|(successor, _)| match successor {
+                Successor::Block(next) => Some(*next),
+                Successor::Exit => None,
+            }));
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "digraph \"{}\" {{", escape(title)).unwrap();
+    writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+    writeln!(out, "  start [shape=point];").unwrap();
+    writeln!(out, "  exit [shape=doublecircle, label=\"end\"];").unwrap();
+    for (id, block) in blocks.iter().enumerate() {
+        let mut label = String::new();
+        for name in labels.get(&block.start).into_iter().flatten() {
+            label += &format!("{}:\\l", escape(name));
+        }
+        // each source line is shown once, next to its first instruction
+        let rows: Vec<(String, String)> = (block.start..block.end).map(|index| {
+            let location = program.source.location(index);
+            let previous = index.checked_sub(1).filter(|&previous| previous >= block.start)
+                .and_then(|previous| program.source.location(previous));
+            let snippet = match location {
+                Some(location) if previous.is_some_and(|p| (&p.file, p.line) == (&location.file, location.line)) => String::new(),
+                Some(location) if location.file == title => format!("{}: {}", location.line, location.text),
+                Some(location) => format!("{}:{}: {}", location.file, location.line, location.text),
+                None => String::new(),
+            };
+            (format!("{:>4}  {}", index, text(&program.instructions[index], &labels)), snippet)
+        }).collect();
+        let width = rows.iter().map(|(instruction, _)| instruction.len()).max().unwrap_or(0);
+        for (instruction, snippet) in rows {
+            let row = format!("{:width$}  {}", instruction, snippet);
+            label += &format!("{}\\l", escape(row.trim_end()));
+        }
+        let style = if reachable[id] { "" } else { ", style=dashed" };
+        writeln!(out, "  b{} [label=\"{}\"{}];", id, label, style).unwrap();
+    }
+    if let Some(entry) = blocks.iter().position(|block| block.start == program.entry) {
+        writeln!(out, "  start -> b{};", entry).unwrap();
+    } else {
+        writeln!(out, "  start -> exit;").unwrap();
+    }
+    for (id, block) in blocks.iter().enumerate() {
+        let last = program.instructions[block.end - 1];
+        for (successor, edge) in &block.successors {
+            let to = match successor {
+                Successor::Block(next) => format!("b{}", next),
+                Successor::Exit => "exit".to_string(),
+            };
+            let label = match (edge, last) {
+                (Edge::Always, _) => String::new(),
+                (Edge::Zero, Instruction::LOOP(_, reg)) => format!(" [label=\"{} == 0\"]", reg),
+                (Edge::NotZero, Instruction::LOOP(_, reg)) => format!(" [label=\"{} != 0\"]", reg),
+                (Edge::Zero, _) => " [label=\"zero\"]".to_string(),
+                (Edge::NotZero, _) => " [label=\"not zero\"]".to_string(),
+            };
+            writeln!(out, "  b{} -> {}{};", id, to, label).unwrap();
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::program::{ SourceLocation, Symbol };
+    use crate::modules::vm::{ Reg, Source };
+    use Instruction::*;
+
+    // MOV C 3 / TOP: PRINT C / LOOP TOP C / HALT / CLS
+    fn counter() -> Program {
+        let mut program = Program {
+            instructions: vec![MOV(Reg::C, Source::Lit(3)), PRINT(Reg::C, true), LOOP(1, Reg::C), HALT, CLS],
+            ..Program::default()
+        };
+        program.source.symbols = vec![Symbol { name: "TOP".to_string(), value: 1, kind: SymbolKind::Label }];
+        program.source.locations = ["MOV C 3", "TOP: PRINT C", "LOOP TOP C", "HALT", "CLS"].iter().enumerate()
+            .map(|(line, text)| SourceLocation { file: "count.e8".to_string(), line: line + 1, text: text.to_string(), expanded_at: None })
+            .collect();
+        program
+    }
+
+    #[test]
+    fn blocks() {
+        let blocks = basic_blocks(&counter());
+        let found: Vec<_> = blocks.iter().map(|block| (block.start, block.end, block.successors.clone())).collect();
+        assert_eq!(found, [
+            (0, 1, vec![(Successor::Block(1), Edge::Always)]),
+            (1, 3, vec![(Successor::Block(1), Edge::NotZero), (Successor::Block(2), Edge::Zero)]),
+            (3, 4, vec![(Successor::Exit, Edge::Always)]),
+            (4, 5, vec![(Successor::Exit, Edge::Always)]),
+        ]);
+    }
+
+    #[test]
+    fn graph() {
+        let dot = dot(&counter(), "count.e8");
+        let lines: Vec<&str> = dot.lines().collect();
+        assert_eq!(lines[..4], [
+            "digraph \"count.e8\" {",
+            "  node [shape=box, fontname=\"monospace\"];",
+            "  start [shape=point];",
+            "  exit [shape=doublecircle, label=\"end\"];",
+        ]);
+        assert_eq!(lines[4..], [
+            "  b0 [label=\"   0  MOV C 3  1: MOV C 3\\l\"];",
+            "  b1 [label=\"TOP:\\l   1  PRINT C     2: TOP: PRINT C\\l   2  LOOP TOP C  3: LOOP TOP C\\l\"];",
+            "  b2 [label=\"   3  HALT  4: HALT\\l\"];",
+            // not reachable from the entry point
+            "  b3 [label=\"   4  CLS  5: CLS\\l\", style=dashed];",
+            "  start -> b0;",
+            "  b0 -> b1;",
+            "  b1 -> b1 [label=\"C != 0\"];",
+            "  b1 -> b2 [label=\"C == 0\"];",
+            "  b2 -> exit;",
+            "  b3 -> exit;",
+            "}",
+        ]);
+    }
+}
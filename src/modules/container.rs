@@ -0,0 +1,317 @@
+use crate::modules::bytecode::{ decode_instruction, encode_instruction, ISA_VERSION };
+use crate::modules::program::{ DataBlock, Program, SourceInfo, Symbol, SymbolKind };
+
+// Assembled programs saved by `e8bit asm`, so `e8bit run` does not have to
+// parse the source again.
+//
+// All numbers are little endian:
+//   magic "E8BT", format version (1 byte), ISA version (1 byte)
+//   entry point: instruction index (2 bytes)
+//   code: instruction count (2), byte length (2), then the bytecode with
+//         jump targets as instruction indices
+//   data: block count (2), then per block its address (1), length (2), bytes
+//   symbols: count (2), then per symbol its kind (1, 0 = label,
+//         1 = constant), value (8), name length (1) and name
+//   checksum: CRC-32 of everything before it (4)
+//
+// Object files (see `object.rs`) share the header, sections and checksum.
+
+pub const MAGIC: &[u8; 4] = b"E8BT";
+const FORMAT_VERSION: u8 = 1;
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write_program(program: &Program) -> Result<Vec<u8>, String> {
+    let mut out = header(MAGIC);
+    push_u16(&mut out, program.entry, "Entry point")?;
+    write_body(&mut out, program)?;
+    Ok(seal(out))
+}
+
+pub fn read_program(bytes: &[u8]) -> Result<Program, String> {
+    let mut reader = open(bytes, MAGIC, "an e8bit binary")?;
+    let entry = reader.u16()?;
+    let mut program = read_body(&mut reader)?;
+    if entry >= program.instructions.len().max(1) {
+        return Err(format!("Entry point {} is past the end of the program", entry));
+    }
+    program.entry = entry;
+    reader.end()?;
+    Ok(program)
+}
+
+// Magic and versions.
+pub fn header(magic: &[u8; 4]) -> Vec<u8> {
+    let mut out = magic.to_vec();
+    out.push(FORMAT_VERSION);
+    out.push(ISA_VERSION);
+    out
+}
+
+// Appends the checksum.
+pub fn seal(mut out: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32(&out);
+    out.extend(checksum.to_le_bytes());
+    out
+}
+
+// Checks magic, checksum and versions; the reader starts after the header.
+pub fn open<'a>(bytes: &'a [u8], magic: &[u8; 4], what: &str) -> Result<Reader<'a>, String> {
+    if !bytes.starts_with(magic) {
+        return Err(format!("Not {}", what));
+    }
+    if bytes.len() < magic.len() + 2 + 4 {
+        return Err("File is too short".to_string());
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32(body).to_le_bytes() != checksum {
+        return Err("Checksum mismatch, the file is damaged".to_string());
+    }
+    let mut reader = Reader { bytes: body, pos: magic.len() };
+    let format = reader.byte()?;
+    if format != FORMAT_VERSION {
+        return Err(format!("Unsupported format version {} (expected {})", format, FORMAT_VERSION));
+    }
+    let isa = reader.byte()?;
+    if isa != ISA_VERSION {
+        return Err(format!("Program was built for ISA version {}, this emulator runs version {}", isa, ISA_VERSION));
+    }
+    Ok(reader)
+}
+
+// The code, data and symbol sections.
+pub fn write_body(out: &mut Vec<u8>, program: &Program) -> Result<(), String> {
+    let mut code = Vec::new();
+    for instruction in &program.instructions {
+        encode_instruction(instruction, &mut code)?;
+    }
+    push_u16(out, program.instructions.len(), "Instruction count")?;
+    push_u16(out, code.len(), "Code size")?;
+    out.extend(code);
+
+    push_u16(out, program.data.len(), "Data block count")?;
+    for block in &program.data {
+        out.push(block.addr);
+        push_u16(out, block.bytes.len(), "Data block size")?;
+        out.extend(&block.bytes);
+    }
+
+    push_u16(out, program.source.symbols.len(), "Symbol count")?;
+    for symbol in &program.source.symbols {
+        out.push(match symbol.kind {
+            SymbolKind::Label => 0,
+            SymbolKind::Constant => 1,
+        });
+        out.extend(symbol.value.to_le_bytes());
+        push_name(out, &symbol.name)?;
+    }
+    Ok(())
+}
+
+pub fn read_body(reader: &mut Reader) -> Result<Program, String> {
+    let count = reader.u16()?;
+    let size = reader.u16()?;
+    let code = reader.take(size)?;
+    let mut instructions = Vec::with_capacity(count);
+    let mut pos = 0;
+    while pos < code.len() {
+        let (instruction, size) = decode_instruction(&code[pos..]).map_err(|e| format!("{} at code byte {}", e, pos))?;
+        instructions.push(instruction);
+        pos += size;
+    }
+    if instructions.len() != count {
+        return Err(format!("Expected {} instructions, found {}", count, instructions.len()));
+    }
+
+    let mut data = Vec::new();
+    let blocks = reader.u16()?;
+    for _ in 0..blocks {
+        let addr = reader.byte()?;
+        let len = reader.u16()?;
+        if addr as usize + len > 256 {
+            return Err(format!("Data at address {} does not fit in memory", addr));
+        }
+        data.push(DataBlock { addr, bytes: reader.take(len)?.to_vec() });
+    }
+
+    let mut symbols = Vec::new();
+    let count = reader.u16()?;
+    for _ in 0..count {
+        let kind = match reader.byte()? {
+            0 => SymbolKind::Label,
+            1 => SymbolKind::Constant,
+            kind => return Err(format!("Invalid symbol kind: {}", kind)),
+        };
+        let value = i64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+        let name = reader.name()?;
+        symbols.push(Symbol { name, value, kind });
+    }
+
+    Ok(Program { instructions, data, entry: 0, source: SourceInfo { locations: Vec::new(), symbols, data: Vec::new() } })
+}
+
+pub fn push_u16(out: &mut Vec<u8>, value: usize, what: &str) -> Result<(), String> {
+    let value = u16::try_from(value).map_err(|_| format!("{} {} does not fit in 16 bits", what, value))?;
+    out.extend(value.to_le_bytes());
+    Ok(())
+}
+
+// A name: its length (1 byte), then its bytes.
+pub fn push_name(out: &mut Vec<u8>, name: &str) -> Result<(), String> {
+    let len = u8::try_from(name.len()).map_err(|_| format!("Symbol name is too long: {}", name))?;
+    out.push(len);
+    out.extend(name.as_bytes());
+    Ok(())
+}
+
+// CRC-32 as used by zip and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes.get(self.pos..self.pos + len).ok_or("Unexpected end of file")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<usize, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+    }
+
+    pub fn name(&mut self) -> Result<String, String> {
+        let len = self.byte()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "Invalid symbol name".to_string())
+    }
+
+    // Fails if anything is left before the checksum.
+    pub fn end(&self) -> Result<(), String> {
+        if self.pos != self.bytes.len() {
+            return Err("Unexpected bytes at the end of the file".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Also used by the object file tests.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::modules::vm::{ Instruction, MemSrc, Reg, Source };
+
+    pub(crate) fn sample() -> Program {
+        let instructions = vec![
+            Instruction::MOV(Reg::A, Source::Lit(200)),
+            Instruction::STORE(Reg::A, MemSrc::Addr(16)),
+            Instruction::ADD(Reg::B, Source::Mem(MemSrc::Reg(Reg::C))),
+            Instruction::MULH(Reg::D, Reg::A, Reg::B),
+            Instruction::LOOP(0, Reg::E),
+            Instruction::PRINT(Reg::A, false),
+            Instruction::DRAW(Source::Reg(Reg::A), Source::Lit(3), Source::Mem(MemSrc::Addr(17))),
+            Instruction::SLP(1000),
+            Instruction::FPS(30),
+            Instruction::HALT,
+        ];
+        let data = vec![
+            DataBlock { addr: 16, bytes: vec![1, 2, 3] },
+            DataBlock { addr: 250, bytes: vec![0; 6] },
+        ];
+        let symbols = vec![
+            Symbol { name: "START".to_string(), value: 0, kind: SymbolKind::Label },
+            Symbol { name: "BIG".to_string(), value: -1 << 40, kind: SymbolKind::Constant },
+        ];
+        Program { instructions, data, entry: 4, source: SourceInfo { symbols, ..SourceInfo::default() } }
+    }
+
+    fn parts(program: &Program) -> String {
+        format!("{:?} {:?} {} {:?}", program.instructions, program.data, program.entry, program.source.symbols)
+    }
+
+    // The bytes with the checksum made to match again after a change.
+    pub(crate) fn resealed(bytes: &[u8], change: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut body = bytes[..bytes.len() - 4].to_vec();
+        change(&mut body);
+        seal(body)
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = sample();
+        let bytes = write_program(&program).unwrap();
+        assert!(is_binary(&bytes));
+        assert_eq!(parts(&read_program(&bytes).unwrap()), parts(&program));
+    }
+
+    #[test]
+    fn empty_program() {
+        let bytes = write_program(&Program::default()).unwrap();
+        let program = read_program(&bytes).unwrap();
+        assert!(program.instructions.is_empty() && program.data.is_empty());
+    }
+
+    #[test]
+    fn checksum() {
+        // the check value of CRC-32
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let bytes = write_program(&sample()).unwrap();
+        for pos in [0, 6, bytes.len() / 2, bytes.len() - 1] {
+            let mut damaged = bytes.clone();
+            damaged[pos] ^= 0x10;
+            let expected = if pos == 0 { "Not an e8bit binary" } else { "Checksum mismatch, the file is damaged" };
+            assert_eq!(read_program(&damaged).unwrap_err(), expected);
+        }
+    }
+
+    #[test]
+    fn rejected_headers() {
+        let bytes = write_program(&sample()).unwrap();
+        assert_eq!(read_program(b"E8BT").unwrap_err(), "File is too short");
+        assert_eq!(read_program(b"E8BO\x01\x01\0\0\0\0").unwrap_err(), "Not an e8bit binary");
+        let format = resealed(&bytes, |body| body[4] = 9);
+        assert_eq!(read_program(&format).unwrap_err(), "Unsupported format version 9 (expected 1)");
+        let isa = resealed(&bytes, |body| body[5] = ISA_VERSION + 1);
+        assert!(read_program(&isa).unwrap_err().starts_with("Program was built for ISA version"));
+    }
+
+    #[test]
+    fn rejected_contents() {
+        let past_end = Program { entry: 10, ..sample() };
+        let bytes = write_program(&past_end).unwrap();
+        assert_eq!(read_program(&bytes).unwrap_err(), "Entry point 10 is past the end of the program");
+        let bytes = write_program(&sample()).unwrap();
+        let trailing = resealed(&bytes, |body| body.push(0));
+        assert_eq!(read_program(&trailing).unwrap_err(), "Unexpected bytes at the end of the file");
+        let truncated = resealed(&bytes, |body| body.truncate(body.len() - 1));
+        assert_eq!(read_program(&truncated).unwrap_err(), "Unexpected end of file");
+        let opcode = resealed(&bytes, |body| body[12] = 0xFF);
+        assert_eq!(read_program(&opcode).unwrap_err(), "Invalid opcode: 0xff at code byte 0");
+    }
+
+    #[test]
+    fn limits_are_errors() {
+        let long = "X".repeat(256);
+        let mut program = sample();
+        program.source.symbols.push(Symbol { name: long.clone(), value: 0, kind: SymbolKind::Constant });
+        assert_eq!(write_program(&program).unwrap_err(), format!("Symbol name is too long: {}", long));
+    }
+}
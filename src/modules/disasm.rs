@@ -0,0 +1,88 @@
+use std::collections::{ HashMap, HashSet };
+use std::fmt::Write;
+use crate::modules::vm::Instruction;
+use crate::modules::program::{ Program, Symbol, SymbolKind };
+
+// Turns programs back into `.e8` source that assembles to the same
+// instructions and data. Names come from the symbol table when there is one;
+// jump targets without a name get a label `L<index>`.
+pub fn disassemble(program: &Program) -> String {
+    let mut out = String::new();
+    let symbols = &program.source.symbols;
+    let constants: Vec<&Symbol> = symbols.iter().filter(|symbol| symbol.kind == SymbolKind::Constant).collect();
+    for constant in &constants {
+        writeln!(out, "{} = {}", constant.name, constant.value).unwrap();
+    }
+    if !constants.is_empty() {
+        writeln!(out).unwrap();
+    }
+    for block in &program.data {
+        writeln!(out, ".org {}", block.addr).unwrap();
+        for chunk in block.bytes.chunks(16) {
+            let bytes: Vec<String> = chunk.iter().map(u8::to_string).collect();
+            writeln!(out, ".byte {}", bytes.join(", ")).unwrap();
+        }
+    }
+    if !program.data.is_empty() {
+        writeln!(out).unwrap();
+    }
+    // there is no directive for it: `asm` and `link` always start at 0
+    if program.entry != 0 {
+        writeln!(out, "// entry point: instruction {}", program.entry).unwrap();
+    }
+    out += &code(&program.instructions, symbols);
+    out
+}
+
+// The instructions alone, one per line, with their labels.
+pub fn code(instructions: &[Instruction], symbols: &[Symbol]) -> String {
+    let labels = labels(instructions, symbols);
+    let mut out = String::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        for label in labels.get(&index).into_iter().flatten() {
+            writeln!(out, "{}:", label).unwrap();
+        }
+        writeln!(out, "  {}", text(instruction, &labels)).unwrap();
+    }
+    for label in labels.get(&instructions.len()).into_iter().flatten() {
+        writeln!(out, "{}:", label).unwrap();
+    }
+    out
+}
+
+// Label names by instruction index: every label from the symbol table, plus
+// `L<index>` for jump targets that have none.
+pub fn labels(instructions: &[Instruction], symbols: &[Symbol]) -> HashMap<usize, Vec<String>> {
+    let mut labels: HashMap<usize, Vec<String>> = HashMap::new();
+    for symbol in symbols.iter().filter(|symbol| symbol.kind == SymbolKind::Label) {
+        if (0..=instructions.len() as i64).contains(&symbol.value) {
+            labels.entry(symbol.value as usize).or_default().push(symbol.name.clone());
+        }
+    }
+    let taken: HashSet<&str> = symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+    let mut missing: Vec<usize> = instructions.iter()
+        .filter_map(Instruction::jump_target)
+        .filter(|target| *target <= instructions.len() && !labels.contains_key(target))
+        .collect();
+    missing.sort();
+    missing.dedup();
+    for target in missing {
+        let mut name = format!("L{}", target);
+        while taken.contains(name.as_str()) {
+            name.insert(0, '_');
+        }
+        labels.insert(target, vec![name]);
+    }
+    labels
+}
+
+// The instruction as source, jumping to the first name of its target.
+pub fn text(instruction: &Instruction, labels: &HashMap<usize, Vec<String>>) -> String {
+    let text = instruction.to_string();
+    let Some(target) = instruction.jump_target() else { return text };
+    let Some(label) = labels.get(&target).map(|names| &names[0]) else { return text };
+    match *instruction {
+        Instruction::LOOP(_, reg) => format!("LOOP {} {}", label, reg),
+        _ => format!("{} {}", text.split(' ').next().unwrap(), label),
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+use crate::modules::lexer::{ SourceFile, Span };
+
+#[derive(Debug, Clone)]
+pub struct AsmError {
+    pub span: Span,
+    pub message: String,
+    // Extra locations worth showing, e.g. the macro calls that produced `span`.
+    pub notes: Vec<(Span, String)>,
+}
+
+impl AsmError {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        AsmError { span, message: message.into(), notes: Vec::new() }
+    }
+
+    pub fn with_note(mut self, span: Span, note: impl Into<String>) -> Self {
+        self.notes.push((span, note.into()));
+        self
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.col, self.message)
+    }
+}
+
+// Prints the error with the offending source line and a caret under it,
+// followed by its notes.
+pub fn report(files: &[SourceFile], err: &AsmError) {
+    eprintln!("error: {}", located(files, err.span, &err.message));
+    show_line(files, err.span);
+    for (span, note) in &err.notes {
+        eprintln!("note: {}", located(files, *span, note));
+        show_line(files, *span);
+    }
+}
+
+fn located(files: &[SourceFile], span: Span, message: &str) -> String {
+    match files.get(span.file) {
+        Some(file) => format!("{}:{}:{}: {}", file.path.display(), span.line, span.col, message),
+        None => format!("{}:{}: {}", span.line, span.col, message),
+    }
+}
+
+fn show_line(files: &[SourceFile], span: Span) {
+    let Some(file) = files.get(span.file) else { return };
+    if let Some(line) = file.text.lines().nth(span.line.wrapping_sub(1)) {
+        let number = span.line.to_string();
+        eprintln!("{} | {}", number, line);
+        eprintln!(
+            "{} | {}{}",
+            " ".repeat(number.len()),
+            " ".repeat(span.col.saturating_sub(1)),
+            "^".repeat(span.len.max(1))
+        );
+    }
+}
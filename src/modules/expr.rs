@@ -0,0 +1,520 @@
+// Compile-time expressions in operands and directives, e.g. `WIDTH-1`,
+// `[BASE+3]`, `'A'+2`, `$+2` or `hi(TABLE)`.
+//
+// Operators, loosest binding first: `||`, `&&`, `|`, `^`, `&`, `== !=`,
+// `< <= > >=`, `<< >>`, `+ -`, `* / %`, then unary `- ~ + !`. Comparisons
+// and logical operators give 1 or 0. `$` is the index of the current
+// instruction and `hi(x)`/`lo(x)` give the high and low byte of a 16-bit value.
+
+use std::ops::Range;
+
+pub trait Scope {
+    // Value of a label or constant, `None` if the name is unknown.
+    fn symbol(&self, name: &str) -> Option<Result<i64, String>>;
+    // Value of `$`, `None` where it is not allowed.
+    fn here(&self) -> Option<i64>;
+}
+
+pub fn evaluate(text: &str, scope: &dyn Scope) -> Result<i64, String> {
+    evaluate_at(text, scope).map_err(|(message, _)| message)
+}
+
+// Like `evaluate`, but an error also gives the characters of `text` holding
+// the operator that failed, if it was an operator that failed.
+pub fn evaluate_at(text: &str, scope: &dyn Scope) -> Result<i64, (String, Option<Range<usize>>)> {
+    let mut parser = Parser { chars: text.chars().collect(), pos: 0, scope, operator: None };
+    let result = parser.logical_or().and_then(|value| {
+        parser.skip_spaces();
+        match parser.chars.get(parser.pos) {
+            Some(c) => Err(format!("Unexpected '{}' in expression: {}", c, text)),
+            None => Ok(value),
+        }
+    });
+    result.map_err(|message| (message, parser.operator))
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    scope: &'a dyn Scope,
+    // where the operator an error is about is
+    operator: Option<Range<usize>>,
+}
+
+impl Parser<'_> {
+    // The operator just eaten, `len` characters long.
+    fn last(&self, len: usize) -> Range<usize> {
+        self.pos - len..self.pos
+    }
+
+    // `value`, or an error about the operator at `operator` if there is none.
+    fn checked<T>(&mut self, operator: Range<usize>, value: Option<T>, message: &str) -> Result<T, String> {
+        value.ok_or_else(|| {
+            self.operator = Some(operator);
+            message.to_string()
+        })
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        self.skip_spaces();
+        let end = self.pos + op.chars().count();
+        if end <= self.chars.len() && self.chars[self.pos..end].iter().copied().eq(op.chars()) {
+            self.pos = end;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn logical_or(&mut self) -> Result<i64, String> {
+        let mut value = self.logical_and()?;
+        while self.eat("||") {
+            let rhs = self.logical_and()?;
+            value = (value != 0 || rhs != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    fn logical_and(&mut self) -> Result<i64, String> {
+        let mut value = self.or()?;
+        while self.eat("&&") {
+            let rhs = self.or()?;
+            value = (value != 0 && rhs != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    fn peek_str(&mut self, op: &str) -> bool {
+        let start = self.pos;
+        let found = self.eat(op);
+        self.pos = start;
+        found
+    }
+
+    fn or(&mut self) -> Result<i64, String> {
+        let mut value = self.xor()?;
+        while !self.peek_str("||") && self.eat("|") {
+            value |= self.xor()?;
+        }
+        Ok(value)
+    }
+
+    fn xor(&mut self) -> Result<i64, String> {
+        let mut value = self.and()?;
+        while self.eat("^") {
+            value ^= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<i64, String> {
+        let mut value = self.equality()?;
+        while !self.peek_str("&&") && self.eat("&") {
+            value &= self.equality()?;
+        }
+        Ok(value)
+    }
+
+    fn equality(&mut self) -> Result<i64, String> {
+        let mut value = self.relation()?;
+        loop {
+            if self.eat("==") {
+                value = (value == self.relation()?) as i64;
+            } else if self.eat("!=") {
+                value = (value != self.relation()?) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn relation(&mut self) -> Result<i64, String> {
+        let mut value = self.shift()?;
+        loop {
+            if self.eat("<=") {
+                value = (value <= self.shift()?) as i64;
+            } else if self.eat(">=") {
+                value = (value >= self.shift()?) as i64;
+            } else if !self.peek_str("<<") && self.eat("<") {
+                value = (value < self.shift()?) as i64;
+            } else if !self.peek_str(">>") && self.eat(">") {
+                value = (value > self.shift()?) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn shift(&mut self) -> Result<i64, String> {
+        let mut value = self.sum()?;
+        loop {
+            if self.eat("<<") {
+                let operator = self.last(2);
+                let by = self.sum()?;
+                let by = self.checked(operator.clone(), shift_amount(by), &format!("Invalid shift amount: {}", by))?;
+                // bits shifted out, or into the sign, are an overflow
+                let shifted = Some(value << by).filter(|shifted| shifted >> by == value);
+                value = self.checked(operator, shifted, "Overflow in expression")?;
+            } else if self.eat(">>") {
+                let operator = self.last(2);
+                let by = self.sum()?;
+                let by = self.checked(operator, shift_amount(by), &format!("Invalid shift amount: {}", by))?;
+                value >>= by;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sum(&mut self) -> Result<i64, String> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat("+") {
+                let operator = self.last(1);
+                let rhs = self.product()?;
+                value = self.checked(operator, value.checked_add(rhs), "Overflow in expression")?;
+            } else if self.eat("-") {
+                let operator = self.last(1);
+                let rhs = self.product()?;
+                value = self.checked(operator, value.checked_sub(rhs), "Overflow in expression")?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<i64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat("*") {
+                let operator = self.last(1);
+                let rhs = self.unary()?;
+                value = self.checked(operator, value.checked_mul(rhs), "Overflow in expression")?;
+            } else if self.eat("/") {
+                let operator = self.last(1);
+                let divisor = self.unary()?;
+                let divisor = self.checked(operator.clone(), Some(divisor).filter(|&d| d != 0), "Division by zero in expression")?;
+                // only i64::MIN / -1
+                value = self.checked(operator, value.checked_div(divisor), "Overflow in expression")?;
+            } else if self.eat("%") {
+                let operator = self.last(1);
+                let divisor = self.unary()?;
+                let divisor = self.checked(operator.clone(), Some(divisor).filter(|&d| d != 0), "Division by zero in expression")?;
+                value = self.checked(operator, value.checked_rem(divisor), "Overflow in expression")?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        if self.eat("-") {
+            let operator = self.last(1);
+            let value = self.unary()?;
+            self.checked(operator, value.checked_neg(), "Overflow in expression")
+        } else if self.eat("~") {
+            Ok(!self.unary()?)
+        } else if !self.peek_str("!=") && self.eat("!") {
+            Ok((self.unary()? == 0) as i64)
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        if let Some(value) = self.literal()? {
+            return Ok(value);
+        }
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = self.logical_or()?;
+                if !self.eat(")") {
+                    return Err("Missing ')' in expression".to_string());
+                }
+                Ok(value)
+            }
+            Some('$') => {
+                self.pos += 1;
+                self.scope.here().ok_or_else(|| "'$' can only be used in instructions".to_string())
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let name = self.name();
+                if self.eat("(") {
+                    let arg = self.logical_or()?;
+                    if !self.eat(")") {
+                        return Err(format!("Missing ')' after {}(", name));
+                    }
+                    match name.as_str() {
+                        "hi" => Ok((arg >> 8) & 0xFF),
+                        "lo" => Ok(arg & 0xFF),
+                        _ => Err(format!("Unknown function: {}", name)),
+                    }
+                } else {
+                    self.scope.symbol(&name).unwrap_or_else(|| Err(format!("Unknown label or constant: {}", name)))
+                }
+            }
+            Some(c) => Err(format!("Unexpected '{}' in expression", c)),
+            None => Err("Missing value in expression".to_string()),
+        }
+    }
+
+    fn name(&mut self) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.') {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    // A number (decimal, 0x hex, 0b binary or 0o octal) or a character
+    // literal such as 'p' or '\n'.
+    fn literal(&mut self) -> Result<Option<i64>, String> {
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                parse_number(&text).map(Some).ok_or_else(|| format!("Invalid number: {}", text))
+            }
+            Some('\'') => {
+                self.pos += 1;
+                let (value, next) = parse_char(&self.chars, self.pos, '\'')?;
+                if self.chars.get(next) != Some(&'\'') {
+                    return Err("Character literal must contain exactly one character".to_string());
+                }
+                self.pos = next + 1;
+                Ok(Some(value as i64))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let (digits, radix) = match text.get(..2) {
+        Some("0x") | Some("0X") => (&text[2..], 16),
+        Some("0b") | Some("0B") => (&text[2..], 2),
+        Some("0o") | Some("0O") => (&text[2..], 8),
+        _ => (&text[..], 10),
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+// Reads one character of a character or string literal starting at `pos`,
+// handling escapes; returns its byte value and the position after it.
+fn parse_char(chars: &[char], pos: usize, quote: char) -> Result<(u8, usize), String> {
+    match chars.get(pos) {
+        None => Err("Unterminated literal".to_string()),
+        Some(&c) if c == quote => Err("Empty character literal".to_string()),
+        Some('\\') => {
+            let escaped = *chars.get(pos + 1).ok_or("Unterminated escape sequence")?;
+            let value = match escaped {
+                'n' => b'\n',
+                't' => b'\t',
+                'r' => b'\r',
+                '0' => 0,
+                '\\' => b'\\',
+                '\'' => b'\'',
+                '"' => b'"',
+                'x' => {
+                    let hex: String = chars.get(pos + 2..pos + 4).ok_or("\\x needs two hex digits")?.iter().collect();
+                    let value = u8::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape: \\x{}", hex))?;
+                    return Ok((value, pos + 4));
+                }
+                other => return Err(format!("Unknown escape sequence: \\{}", other)),
+            };
+            Ok((value, pos + 2))
+        }
+        Some(&c) => {
+            let value = u8::try_from(c as u32).map_err(|_| format!("Character '{}' does not fit in 8 bits", c))?;
+            Ok((value, pos + 1))
+        }
+    }
+}
+
+// The bytes of a double-quoted string literal, with escapes resolved.
+pub fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let chars: Vec<char> = text.trim().chars().collect();
+    if chars.len() < 2 || chars[0] != '"' || chars[chars.len() - 1] != '"' {
+        return Err(format!("Expected a quoted string: {}", text));
+    }
+    let end = chars.len() - 1;
+    let mut bytes = Vec::new();
+    let mut pos = 1;
+    while pos < end {
+        if chars[pos] == '"' {
+            return Err(format!("Unescaped '\"' inside string: {}", text));
+        }
+        let (value, next) = parse_char(&chars[..end], pos, '"')?;
+        bytes.push(value);
+        pos = next;
+    }
+    Ok(bytes)
+}
+
+fn shift_amount(by: i64) -> Option<u32> {
+    u32::try_from(by).ok().filter(|&by| by < 64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Names;
+
+    impl Scope for Names {
+        fn symbol(&self, name: &str) -> Option<Result<i64, String>> {
+            match name {
+                "WIDTH" => Some(Ok(80)),
+                "BROKEN" => Some(Err("Broken constant".to_string())),
+                _ => None,
+            }
+        }
+
+        fn here(&self) -> Option<i64> {
+            Some(7)
+        }
+    }
+
+    fn eval(text: &str) -> Result<i64, String> {
+        evaluate(text, &Names)
+    }
+
+    const MIN: &str = "(-9223372036854775807-1)";
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 3 - 2"), Ok(5));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("6 & 3 == 2"), Ok(0));
+        assert_eq!(eval("1 || 0 && 0"), Ok(1));
+        assert_eq!(eval("1 < 2 == 1"), Ok(1));
+        assert_eq!(eval("5 | 2 ^ 3"), Ok(5));
+        assert_eq!(eval("-2 * 3"), Ok(-6));
+        assert_eq!(eval("~0"), Ok(-1));
+        assert_eq!(eval("!5 + !0"), Ok(1));
+        assert_eq!(eval("7 / 2"), Ok(3));
+        assert_eq!(eval("-7 % 3"), Ok(-1));
+    }
+
+    #[test]
+    fn names_and_functions() {
+        assert_eq!(eval("WIDTH-1"), Ok(79));
+        assert_eq!(eval("$+2"), Ok(9));
+        assert_eq!(eval("hi(4660)"), Ok(18));
+        assert_eq!(eval("lo(4660)"), Ok(52));
+        assert_eq!(eval("HEIGHT"), Err("Unknown label or constant: HEIGHT".to_string()));
+        assert_eq!(eval("BROKEN + 1"), Err("Broken constant".to_string()));
+        assert_eq!(eval("mid(1)"), Err("Unknown function: mid".to_string()));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(eval("(1 + 2"), Err("Missing ')' in expression".to_string()));
+        assert_eq!(eval("1 +"), Err("Missing value in expression".to_string()));
+        assert_eq!(eval("1 2"), Err("Unexpected '2' in expression: 1 2".to_string()));
+    }
+
+    #[test]
+    fn overflow() {
+        let overflow = Err("Overflow in expression".to_string());
+        assert_eq!(eval(&format!("-{}", MIN)), overflow);
+        assert_eq!(eval("9223372036854775807 + 1"), overflow);
+        assert_eq!(eval(&format!("{} - 1", MIN)), overflow);
+        assert_eq!(eval("4611686018427387904 * 2"), overflow);
+        assert_eq!(eval(&format!("{} / -1", MIN)), overflow);
+        assert_eq!(eval(&format!("{} % -1", MIN)), overflow);
+        assert_eq!(eval("1 << 63"), overflow);
+        assert_eq!(eval("3 << 62"), overflow);
+        assert_eq!(eval("1 << 62"), Ok(4611686018427387904));
+        assert_eq!(eval("-1 << 63"), Ok(i64::MIN));
+        assert_eq!(eval(&format!("{} + 1", MIN)), Ok(i64::MIN + 1));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let by_zero = Err("Division by zero in expression".to_string());
+        assert_eq!(eval("1 / 0"), by_zero);
+        assert_eq!(eval("5 % (2 - 2)"), by_zero);
+        assert_eq!(eval(&format!("{} / 0", MIN)), by_zero);
+    }
+
+    #[test]
+    fn shift_amount_checked() {
+        assert_eq!(eval("1 << 64"), Err("Invalid shift amount: 64".to_string()));
+        assert_eq!(eval("8 >> -1"), Err("Invalid shift amount: -1".to_string()));
+        assert_eq!(eval("-8 >> 1"), Ok(-4));
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(eval("0x1F"), Ok(31));
+        assert_eq!(eval("0XfF"), Ok(255));
+        assert_eq!(eval("0b101"), Ok(5));
+        assert_eq!(eval("0o17"), Ok(15));
+        assert_eq!(eval("1_000"), Ok(1000));
+        assert_eq!(eval("0b1111_0000"), Ok(240));
+        assert_eq!(eval("0x10 + 0b1"), Ok(17));
+        assert_eq!(eval("0xZZ"), Err("Invalid number: 0xZZ".to_string()));
+        assert_eq!(eval("0b102"), Err("Invalid number: 0b102".to_string()));
+        assert_eq!(eval("12abc"), Err("Invalid number: 12abc".to_string()));
+        assert_eq!(eval("9223372036854775808"), Err("Invalid number: 9223372036854775808".to_string()));
+    }
+
+    #[test]
+    fn char_literals() {
+        assert_eq!(eval("'A'"), Ok(65));
+        assert_eq!(eval("'A' + 2"), Ok(67));
+        assert_eq!(eval(r"'\n'"), Ok(10));
+        assert_eq!(eval(r"'\0'"), Ok(0));
+        assert_eq!(eval(r"'\''"), Ok(39));
+        assert_eq!(eval(r"'\\'"), Ok(92));
+        assert_eq!(eval(r"'\x41'"), Ok(65));
+        assert_eq!(eval("''"), Err("Empty character literal".to_string()));
+        assert_eq!(eval("'ab'"), Err("Character literal must contain exactly one character".to_string()));
+        assert_eq!(eval("'a"), Err("Character literal must contain exactly one character".to_string()));
+        assert_eq!(eval(r"'\q'"), Err(r"Unknown escape sequence: \q".to_string()));
+        assert_eq!(eval(r"'\xG1'"), Err(r"Invalid escape: \xG1".to_string()));
+        assert_eq!(eval("'é'"), Ok(233));
+        assert_eq!(eval("'€'"), Err("Character '€' does not fit in 8 bits".to_string()));
+    }
+
+    #[test]
+    fn string_literals() {
+        assert_eq!(parse_string(r#""hi\n""#), Ok(b"hi\n".to_vec()));
+        assert_eq!(parse_string(r#" "a\"b" "#), Ok(b"a\"b".to_vec()));
+        assert_eq!(parse_string(r#""""#), Ok(Vec::new()));
+        assert_eq!(parse_string("hi"), Err("Expected a quoted string: hi".to_string()));
+        assert_eq!(parse_string(r#""a"b""#), Err(r#"Unescaped '"' inside string: "a"b""#.to_string()));
+        assert_eq!(parse_string(r#""a\""#), Err("Unterminated escape sequence".to_string()));
+    }
+
+    #[test]
+    fn error_points_at_operator() {
+        let error = |text| evaluate_at(text, &Names).unwrap_err();
+        assert_eq!(error("1 + 2 * 9223372036854775807"), ("Overflow in expression".to_string(), Some(6..7)));
+        assert_eq!(error("-(-9223372036854775807-1)"), ("Overflow in expression".to_string(), Some(0..1)));
+        assert_eq!(error("WIDTH  <<  80"), ("Invalid shift amount: 80".to_string(), Some(7..9)));
+        assert_eq!(error("3 / 0"), ("Division by zero in expression".to_string(), Some(2..3)));
+        assert_eq!(error("NOPE + 1"), ("Unknown label or constant: NOPE".to_string(), None));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::modules::error::AsmError;
+use crate::modules::syntax::{ parse, Body, Element, SyntaxTree };
+
+// `e8bit fmt`: prints source in one style.
+//   - labels on a line of their own at column 0
+//   - instructions and macro calls indented by 2, plus 2 inside each
+//     `.if`, `.while`, `.for` and `.macro` block; other directives and
+//     constant definitions indented by 2 per block only
+//   - a single space between a mnemonic and its operands, and between
+//     operands; `, ` where the source has a comma
+//   - one statement per line, without `;`
+//   - trailing `//` comments of neighbouring lines aligned, two spaces after
+//     the longest line; comment lines indented like the line after them
+//   - no trailing whitespace, no runs of blank lines
+// Spacing inside an operand is kept (collapsed to single spaces), since it
+// decides where operands end: `1 -1` is two operands, `1 - 1` one.
+pub fn format(text: &str) -> Result<String, AsmError> {
+    let tree = parse(text)?;
+    debug_assert_eq!(tree.to_string(), text);
+    Ok(render(rows(&tree)))
+}
+
+const BLOCK_OPEN: &[&str] = &[".if", ".ifdef", ".ifndef", ".while", ".for", ".macro"];
+const BLOCK_CLOSE: &[&str] = &[".endif", ".endwhile", ".endfor", ".endm"];
+
+enum Row {
+    Blank,
+    Comment(String),
+    Code { indent: usize, code: String, comment: Option<String> },
+}
+
+fn rows(tree: &SyntaxTree) -> Vec<Row> {
+    let mut rows = Vec::new();
+    let mut depth = 0usize;
+    // inside the braces of a `.struct` spanning several lines
+    let mut in_struct = false;
+    for line in &tree.lines {
+        let comment = line.comment.as_ref().map(|comment| comment.token.text.trim_end().to_string());
+        if line.statements.iter().all(|statement| statement.labels.is_empty() && statement.body.is_none()) {
+            rows.push(comment.map_or(Row::Blank, Row::Comment));
+            continue;
+        }
+        let first = rows.len();
+        for statement in &line.statements {
+            for label in &statement.labels {
+                rows.push(Row::Code { indent: 0, code: format!("{}:", label.name.token.text), comment: None });
+            }
+            let Some(body) = &statement.body else { continue };
+            let head = body.head.token.text.as_str();
+            let (indent, code) = if in_struct {
+                in_struct = !elements(body).any(|element| element.token.is("}"));
+                let indent = if head == "}" { 2 * depth } else { 2 * depth + 2 };
+                (indent, raw(body))
+            } else if BLOCK_OPEN.contains(&head) {
+                depth += 1;
+                (2 * depth, statement_text(body))
+            } else if head == ".else" {
+                (2 * depth, statement_text(body))
+            } else if BLOCK_CLOSE.contains(&head) {
+                depth = depth.saturating_sub(1);
+                (2 * depth + 2, statement_text(body))
+            } else if head == ".struct" {
+                in_struct = elements(body).any(|element| element.token.is("{"))
+                    && !elements(body).any(|element| element.token.is("}"));
+                (2 * depth, raw(body))
+            } else if head.starts_with('.') || body.equals.is_some() {
+                (2 * depth, statement_text(body))
+            } else {
+                (2 * depth + 2, statement_text(body))
+            };
+            rows.push(Row::Code { indent, code, comment: None });
+        }
+        if rows.len() > first
+            && let Some(Row::Code { comment: slot, .. }) = rows.last_mut()
+        {
+            *slot = comment;
+        }
+    }
+    rows
+}
+
+// The statement with normalized spacing.
+fn statement_text(body: &Body) -> String {
+    let mut text = body.head.token.text.clone();
+    if body.equals.is_some() {
+        text.push_str(" =");
+    }
+    for (i, operand) in body.operands.iter().enumerate() {
+        text.push_str(if i > 0 && !operand.commas.is_empty() { ", " } else { " " });
+        text.push_str(&join(&operand.tokens));
+    }
+    for _ in &body.trailing {
+        text.push(',');
+    }
+    text
+}
+
+// The statement as written, with spacing collapsed; for `.struct`, whose
+// field lists do not split into operands.
+fn raw(body: &Body) -> String {
+    join(elements(body))
+}
+
+fn join<'a>(elements: impl IntoIterator<Item = &'a Element>) -> String {
+    let mut text = String::new();
+    for element in elements {
+        if !text.is_empty() && !element.space.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(&element.token.text);
+    }
+    text
+}
+
+fn elements(body: &Body) -> impl Iterator<Item = &Element> {
+    std::iter::once(&body.head)
+        .chain(&body.equals)
+        .chain(body.operands.iter().flat_map(|operand| operand.commas.iter().chain(&operand.tokens)))
+        .chain(&body.trailing)
+}
+
+fn render(rows: Vec<Row>) -> String {
+    let mut out = String::new();
+    let mut blank = false;
+    for (i, row) in rows.iter().enumerate() {
+        match row {
+            Row::Blank => {
+                blank = !out.is_empty();
+                continue;
+            }
+            _ if blank => out.push('\n'),
+            _ => {}
+        }
+        blank = false;
+        match row {
+            Row::Blank => {}
+            Row::Comment(comment) => {
+                let indent = match rows[i + 1..].iter().find(|row| !matches!(row, Row::Comment(_))) {
+                    Some(Row::Code { indent, .. }) => *indent,
+                    _ => 0,
+                };
+                out += &format!("{}{}\n", " ".repeat(indent), comment);
+            }
+            Row::Code { indent, code, comment } => {
+                out += &" ".repeat(*indent);
+                out += code;
+                if let Some(comment) = comment {
+                    let width = indent + code.chars().count();
+                    out += &" ".repeat(comment_column(&rows, i) - width);
+                    out += comment;
+                }
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+// Where the trailing comments of the run of code lines around `row` start.
+fn comment_column(rows: &[Row], row: usize) -> usize {
+    let is_code = |row: &Row| matches!(row, Row::Code { .. });
+    let start = rows[..row].iter().rposition(|row| !is_code(row)).map_or(0, |i| i + 1);
+    let end = rows[row..].iter().position(|row| !is_code(row)).map_or(rows.len(), |i| row + i);
+    rows[start..end].iter()
+        .filter_map(|row| match row {
+            Row::Code { indent, code, comment: Some(_) } => Some(indent + code.chars().count()),
+            _ => None,
+        })
+        .max()
+        .unwrap()
+        + 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formatted(text: &str) -> String {
+        format(text).unwrap()
+    }
+
+    #[test]
+    fn labels_and_statements() {
+        assert_eq!(formatted("start: MOV   A,5 ; ADD A 1\n  loop:JMP loop\n"),
+            "start:\n  MOV A, 5\n  ADD A 1\nloop:\n  JMP loop\n");
+        assert_eq!(formatted("SIZE=4\n.org 10\n.byte 1,2   3\n"), "SIZE = 4\n.org 10\n.byte 1, 2 3\n");
+    }
+
+    #[test]
+    fn spacing_inside_operands_is_kept() {
+        assert_eq!(formatted("MOV A  1   -   1\nDRAW 1  -1 A\n"), "  MOV A 1 - 1\n  DRAW 1 -1 A\n");
+    }
+
+    #[test]
+    fn blocks_are_indented() {
+        let text = ".if SIZE > 2\nMOV A 1\n.for B = 0 to 3\nSTEP = 2\nADD A STEP\n.endfor\n.else\nHALT\n.endif\n";
+        let expected = "  .if SIZE > 2\n    MOV A 1\n    .for B = 0 to 3\n    STEP = 2\n      ADD A STEP\n    .endfor\n  .else\n    HALT\n  .endif\n";
+        assert_eq!(formatted(text), expected);
+        let text = ".macro TWICE REG\nADD REG REG\n.if REG == 0\nHALT\n.endif\n.endm\nTWICE A\n";
+        let expected = "  .macro TWICE REG\n    ADD REG REG\n    .if REG == 0\n      HALT\n    .endif\n  .endm\n  TWICE A\n";
+        assert_eq!(formatted(text), expected);
+    }
+
+    #[test]
+    fn comments_are_aligned() {
+        let text = "MOV A 1 // one\nADD A 100   // two   \n\n// about halt\nHALT // stop\n";
+        let expected = "  MOV A 1    // one\n  ADD A 100  // two\n\n  // about halt\n  HALT  // stop\n";
+        assert_eq!(formatted(text), expected);
+    }
+
+    #[test]
+    fn blank_lines_are_collapsed() {
+        assert_eq!(formatted("\n\nMOV A 1\n\n\n\nHALT\n\n\n"), "  MOV A 1\n\n  HALT\n");
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let examples = [
+            include_str!("../../examples/example.e8"),
+            include_str!("../../examples/example5.e8"),
+            include_str!("../../examples/game.e8"),
+            include_str!("../../examples/game2.e8"),
+            include_str!("../../examples/lib/common.e8"),
+        ];
+        for text in examples {
+            let once = formatted(text);
+            assert_eq!(formatted(&once), once);
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        assert!(format("MOV A \"open\n").is_err());
+    }
+}
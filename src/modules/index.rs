@@ -0,0 +1,310 @@
+use std::collections::{ HashMap, HashSet };
+use std::path::{ Path, PathBuf };
+use crate::modules::lexer::{ statements, Span, Token, TokenKind };
+
+// Where the labels, constants, macros, structs and arrays of a file and the
+// files it includes are defined and used. Found from the tokens alone, like
+// the assembler's first pass, so that it still works while the program does
+// not assemble; used by the language server.
+pub struct SourceIndex {
+    // the files read, by the `file` of their spans; the first is the one indexed
+    pub files: Vec<(PathBuf, String)>,
+    pub definitions: Vec<Definition>,
+    // each use of a name with the definition it refers to
+    pub references: Vec<(Span, usize)>,
+}
+
+pub struct Definition {
+    // as written, e.g. `Star.x` for a field or `.loop` for a local label
+    pub name: String,
+    pub kind: DefinitionKind,
+    pub span: Span,
+    // for labels and constants: the name the assembler gives the symbol
+    pub symbol: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefinitionKind {
+    Label,
+    Constant,
+    Macro,
+    Struct,
+    Field,
+    Array,
+    Extern,
+}
+
+// `load` returns the text of a file, so that files open in the editor are
+// read as they are there rather than from disk.
+pub fn index(path: &Path, text: &str, include_dirs: &[String], load: &dyn Fn(&Path) -> Option<String>) -> SourceIndex {
+    let mut scanner = Scanner {
+        index: SourceIndex { files: Vec::new(), definitions: Vec::new(), references: Vec::new() },
+        include_dirs,
+        load,
+        seen: HashSet::new(),
+        keys: HashMap::new(),
+        scope: None,
+        recording: None,
+        structure: None,
+        uses: Vec::new(),
+    };
+    scanner.file(path.to_path_buf(), text.to_string());
+    for (token, recording, scope) in std::mem::take(&mut scanner.uses) {
+        if let Some(definition) = scanner.resolve(&token, recording.as_deref(), scope.as_deref()) {
+            scanner.index.references.push((token.span, definition));
+        }
+    }
+    scanner.index
+}
+
+impl SourceIndex {
+    // The definition at the position (1-based line and column, in
+    // characters), or the one used there.
+    pub fn definition_at(&self, file: usize, line: usize, col: usize) -> Option<usize> {
+        let covers = |span: &Span| span.file == file && span.line == line && (span.col..=span.col + span.len).contains(&col);
+        self.definitions.iter().position(|definition| covers(&definition.span))
+            .or_else(|| self.references.iter().find(|(span, _)| covers(span)).map(|(_, definition)| *definition))
+    }
+
+    pub fn uses(&self, definition: usize) -> impl Iterator<Item = &Span> {
+        self.references.iter().filter(move |(_, used)| *used == definition).map(|(span, _)| span)
+    }
+}
+
+// A macro being read: its name and parameters.
+type Recording = (String, Vec<String>);
+
+struct Scanner<'a> {
+    index: SourceIndex,
+    include_dirs: &'a [String],
+    load: &'a dyn Fn(&Path) -> Option<String>,
+    seen: HashSet<PathBuf>,
+    // definitions by the name they are looked up with
+    keys: HashMap<String, usize>,
+    // the last global label, for local labels
+    scope: Option<String>,
+    recording: Option<Recording>,
+    // the struct whose fields are being read
+    structure: Option<String>,
+    // names used, with the macro and scope they were used in
+    uses: Vec<(Token, Option<String>, Option<String>)>,
+}
+
+impl Scanner<'_> {
+    fn file(&mut self, path: PathBuf, text: String) {
+        self.seen.insert(std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone()));
+        let file = self.index.files.len();
+        self.index.files.push((path, text.clone()));
+        for (i, line) in text.lines().enumerate() {
+            // lines that do not split into tokens are left to the assembler's diagnostics
+            for tokens in statements(line, i + 1, file).unwrap_or_default() {
+                self.statement(&tokens, file);
+            }
+        }
+    }
+
+    fn statement(&mut self, tokens: &[Token], file: usize) {
+        let first = &tokens[0];
+        if let Some(structure) = self.structure.clone() {
+            self.fields(&structure, tokens);
+            return;
+        }
+        if tokens.get(1).is_some_and(|colon| colon.is(":")) && matches!(first.kind, TokenKind::Ident | TokenKind::Directive) {
+            let symbol = self.local(first);
+            let key = match &self.recording {
+                Some((name, _)) => format!("{}%{}", name, symbol),
+                None => symbol.clone(),
+            };
+            self.define(key, first, DefinitionKind::Label, symbol);
+            if first.kind == TokenKind::Ident && self.recording.is_none() {
+                self.scope = Some(first.text.clone());
+            }
+            if tokens.len() > 2 {
+                self.statement(&tokens[2..], file);
+            }
+            return;
+        }
+        let name = tokens.get(1).filter(|name| name.kind == TokenKind::Ident);
+        match first.text.as_str() {
+            _ if first.kind == TokenKind::Ident && tokens.get(1).is_some_and(|equals| equals.is("=")) => {
+                self.define(first.text.clone(), first, DefinitionKind::Constant, first.text.clone());
+                self.uses(&tokens[2..]);
+            }
+            ".equ" | ".define" => {
+                if let Some(name) = name {
+                    self.define(name.text.clone(), name, DefinitionKind::Constant, name.text.clone());
+                }
+                self.uses(tokens.get(2..).unwrap_or_default());
+            }
+            ".macro" => {
+                if let Some(name) = name {
+                    self.define(name.text.clone(), name, DefinitionKind::Macro, name.text.clone());
+                    let params = tokens[2..].iter().filter(|token| token.kind == TokenKind::Ident).map(|token| token.text.clone()).collect();
+                    self.recording = Some((name.text.clone(), params));
+                }
+            }
+            ".endm" => self.recording = None,
+            ".struct" => {
+                if let Some(name) = name {
+                    self.define(name.text.clone(), name, DefinitionKind::Struct, name.text.clone());
+                    if let Some(open) = tokens.iter().position(|token| token.is("{")) {
+                        self.structure = Some(name.text.clone());
+                        self.fields(&name.text, &tokens[open + 1..]);
+                    }
+                }
+            }
+            ".array" => {
+                if let Some(name) = name {
+                    self.define(name.text.clone(), name, DefinitionKind::Array, name.text.clone());
+                }
+                self.uses(tokens.get(2..).unwrap_or_default());
+            }
+            ".extern" => {
+                for name in tokens[1..].iter().filter(|token| token.kind == TokenKind::Ident) {
+                    self.define(name.text.clone(), name, DefinitionKind::Extern, name.text.clone());
+                }
+            }
+            ".include" => {
+                if let Some(path) = tokens.get(1).filter(|path| path.kind == TokenKind::Str) {
+                    self.include(&path.text[1..path.text.len() - 1], file);
+                }
+            }
+            _ => self.uses(tokens),
+        }
+    }
+
+    // Field names up to the closing `}`, which may be on a later line.
+    fn fields(&mut self, structure: &str, tokens: &[Token]) {
+        let mut depth = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            if token.is("}") {
+                self.structure = None;
+                return;
+            } else if token.is("[") {
+                depth += 1;
+            } else if token.is("]") {
+                depth -= 1;
+            } else if depth == 0 && token.kind == TokenKind::Ident {
+                let name = format!("{}.{}", structure, token.text);
+                self.define(name.clone(), token, DefinitionKind::Field, name);
+            } else if depth > 0 {
+                self.uses(&tokens[i..i + 1]);
+            }
+        }
+    }
+
+    fn include(&mut self, name: &str, file: usize) {
+        let base = self.index.files[file].0.parent().map(Path::to_path_buf).unwrap_or_default();
+        let candidates = std::iter::once(base.join(name))
+            .chain(self.include_dirs.iter().map(|dir| Path::new(dir).join(name)));
+        for path in candidates {
+            let canonical = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if self.seen.contains(&canonical) {
+                return;
+            }
+            if let Some(text) = (self.load)(&path) {
+                self.file(path, text);
+                return;
+            }
+        }
+    }
+
+    fn define(&mut self, key: String, token: &Token, kind: DefinitionKind, symbol: String) {
+        let name = match kind {
+            DefinitionKind::Field => symbol.clone(),
+            _ => token.text.clone(),
+        };
+        self.keys.entry(key).or_insert(self.index.definitions.len());
+        self.index.definitions.push(Definition { name, kind, span: token.span, symbol });
+    }
+
+    // Every name in the tokens, except macro parameters; local labels after
+    // the first token.
+    fn uses(&mut self, tokens: &[Token]) {
+        for (i, token) in tokens.iter().enumerate() {
+            let is_use = match token.kind {
+                TokenKind::Ident => !self.recording.as_ref().is_some_and(|(_, params)| {
+                    let base = token.text.split('.').next().unwrap();
+                    params.iter().any(|param| param == base)
+                }),
+                TokenKind::Directive => i > 0,
+                _ => false,
+            };
+            if is_use {
+                let recording = self.recording.as_ref().map(|(name, _)| name.clone());
+                self.uses.push((token.clone(), recording, self.scope.clone()));
+            }
+        }
+    }
+
+    // The full name of a label: local labels are prefixed with their scope.
+    fn local(&self, token: &Token) -> String {
+        match (&self.scope, token.kind) {
+            (Some(scope), TokenKind::Directive) => format!("{}{}", scope, token.text),
+            _ => token.text.clone(),
+        }
+    }
+
+    // Names made by the assembler, like `STARS.0.x` or `Star.size`, resolve to
+    // the struct or array they come from.
+    fn resolve(&self, token: &Token, recording: Option<&str>, scope: Option<&str>) -> Option<usize> {
+        let name = match (scope, token.kind) {
+            (Some(scope), TokenKind::Directive) => format!("{}{}", scope, token.text),
+            _ => token.text.clone(),
+        };
+        if let Some(recording) = recording
+            && let Some(&definition) = self.keys.get(&format!("{}%{}", recording, name))
+        {
+            return Some(definition);
+        }
+        let mut name = name.as_str();
+        loop {
+            if let Some(&definition) = self.keys.get(name) {
+                return Some(definition);
+            }
+            if token.kind != TokenKind::Ident {
+                return None;
+            }
+            name = &name[..name.rfind('.')?];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each use as `line:col NAME -> line` of the definition it refers to.
+    fn resolved(text: &str) -> Vec<String> {
+        let index = index(Path::new("test.e8"), text, &[], &|_| None);
+        index.references.iter().map(|(span, definition)| {
+            let definition = &index.definitions[*definition];
+            format!("{}:{} {} -> {}", span.line, span.col, definition.name, definition.span.line)
+        }).collect()
+    }
+
+    #[test]
+    fn local_labels() {
+        let text = "DRAW:\n.loop: JMP .loop\nMOVE:\n.loop: JMP .loop\nJMP DRAW.loop";
+        assert_eq!(resolved(text), ["2:12 .loop -> 2", "4:12 .loop -> 4", "5:5 .loop -> 2"]);
+    }
+
+    #[test]
+    fn macro_labels() {
+        // a label inside a macro is only seen from the macro
+        let text = "DONE: HALT\n.macro WAIT N\nDONE: JNZ DONE\nMOV A N\n.endm\nJMP DONE";
+        assert_eq!(resolved(text), ["3:11 DONE -> 3", "6:5 DONE -> 1"]);
+    }
+
+    #[test]
+    fn struct_and_array_members() {
+        let text = ".struct Star { x, y[SIZE] }\nSIZE = 2\n.array STARS Star 3 at 2\nMOV A [STARS.1.y]\nMOV B Star.size + Star.x";
+        assert_eq!(resolved(text), [
+            "1:21 SIZE -> 2",
+            "3:14 Star -> 1",
+            "4:8 STARS -> 3",
+            "5:7 Star -> 1",
+            "5:19 Star.x -> 1",
+        ]);
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Condvar, Mutex };
+use std::thread::JoinHandle;
+use std::time::{ Duration, Instant };
+use std::io::stdout;
+use crossterm::execute;
+use crossterm::event::{
+    poll, read, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+    KeyboardEnhancementFlags, PushKeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+};
+use crossterm::terminal::{ enable_raw_mode, disable_raw_mode, supports_keyboard_enhancement };
+
+// Codes returned by INKEY for keys that have no ASCII value.
+// Printable keys, Enter (10), Tab (9), Backspace (8), Esc (27) and Delete (127)
+// keep their ASCII codes; everything else lives above 127.
+pub const KEY_UP: u8 = 128;
+pub const KEY_DOWN: u8 = 129;
+pub const KEY_LEFT: u8 = 130;
+pub const KEY_RIGHT: u8 = 131;
+pub const KEY_HOME: u8 = 132;
+pub const KEY_END: u8 = 133;
+pub const KEY_PAGE_UP: u8 = 134;
+pub const KEY_PAGE_DOWN: u8 = 135;
+pub const KEY_INSERT: u8 = 136;
+pub const KEY_F1: u8 = 141; // F1..F12 = 141..152
+
+// Held-key matrix: one bit per key code, bit (code % 8) of
+// mem[KEY_MATRIX_ADDR + code / 8]. Letters are tracked by their lowercase code.
+pub const KEY_MATRIX_ADDR: usize = 224;
+pub const KEY_MATRIX_SIZE: usize = 32;
+
+// Bits returned by KEYMOD.
+pub const MOD_SHIFT: u8 = 1;
+pub const MOD_CTRL: u8 = 2;
+pub const MOD_ALT: u8 = 4;
+
+pub fn key_code(code: KeyCode) -> u8 {
+    match code {
+        KeyCode::Char(c) if c.is_ascii() => c as u8,
+        KeyCode::Enter => b'\n',
+        KeyCode::Tab | KeyCode::BackTab => b'\t',
+        KeyCode::Backspace => 8,
+        KeyCode::Esc => 27,
+        KeyCode::Delete => 127,
+        KeyCode::Up => KEY_UP,
+        KeyCode::Down => KEY_DOWN,
+        KeyCode::Left => KEY_LEFT,
+        KeyCode::Right => KEY_RIGHT,
+        KeyCode::Home => KEY_HOME,
+        KeyCode::End => KEY_END,
+        KeyCode::PageUp => KEY_PAGE_UP,
+        KeyCode::PageDown => KEY_PAGE_DOWN,
+        KeyCode::Insert => KEY_INSERT,
+        KeyCode::F(n @ 1..=12) => KEY_F1 + n - 1,
+        _ => 0,
+    }
+}
+
+pub fn modifier_bits(code: KeyCode, modifiers: KeyModifiers) -> u8 {
+    let mut bits = 0;
+    // crossterm reports Shift+Tab as BackTab, sometimes without the SHIFT modifier
+    if modifiers.contains(KeyModifiers::SHIFT) || code == KeyCode::BackTab {
+        bits |= MOD_SHIFT;
+    }
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        bits |= MOD_CTRL;
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        bits |= MOD_ALT;
+    }
+    bits
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    DropNewest,
+    DropOldest,
+}
+
+impl OverflowPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "drop-newest" => Some(OverflowPolicy::DropNewest),
+            "drop-oldest" => Some(OverflowPolicy::DropOldest),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyPress {
+    pub code: u8,
+    pub mods: u8,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<VecDeque<KeyPress>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    pause: Mutex<Pause>,
+    pause_changed: Condvar,
+    stop: AtomicBool,
+    matrix: Mutex<[u8; KEY_MATRIX_SIZE]>,
+}
+
+impl Shared {
+    // Queues a key press, making room by the overflow policy when full.
+    fn push(&self, key: KeyPress) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropNewest => return,
+                OverflowPolicy::DropOldest => { queue.pop_front(); }
+            }
+        }
+        queue.push_back(key);
+    }
+}
+
+// The handshake that hands stdin over to INPUT.
+#[derive(Debug, Default)]
+struct Pause {
+    // set by `suspend` until `resume`
+    requested: bool,
+    // set by the reader once it has stopped reading keys for it
+    acknowledged: bool,
+}
+
+// Keys are read by a background thread into a bounded queue, so nothing is
+// lost between two INKEYs. The terminal stays in raw mode while it runs.
+#[derive(Debug)]
+pub struct Keyboard {
+    shared: Arc<Shared>,
+    reader: Option<JoinHandle<()>>,
+    matrix_hold: Option<Duration>,
+    enhanced: bool,
+}
+
+impl Keyboard {
+    pub const DEFAULT_CAPACITY: usize = 32;
+
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Keyboard {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+                policy,
+                pause: Mutex::new(Pause::default()),
+                pause_changed: Condvar::new(),
+                stop: AtomicBool::new(false),
+                matrix: Mutex::new([0; KEY_MATRIX_SIZE]),
+            }),
+            reader: None,
+            matrix_hold: None,
+            enhanced: false,
+        }
+    }
+
+    // Tracks held keys for the memory-mapped matrix. `hold` is how long a key
+    // counts as held after its last press or repeat when the terminal does
+    // not report key releases.
+    pub fn enable_matrix(&mut self, hold: Duration) {
+        self.matrix_hold = Some(hold);
+    }
+
+    pub fn has_matrix(&self) -> bool {
+        self.matrix_hold.is_some()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.reader.is_some()
+    }
+
+    pub fn start(&mut self) {
+        if self.reader.is_some() {
+            return;
+        }
+        // without a terminal (e.g. piped input) there are simply no keys
+        if enable_raw_mode().is_err() {
+            return;
+        }
+        // release events are only needed for the matrix; ask for them if the
+        // terminal speaks the kitty keyboard protocol
+        if self.matrix_hold.is_some() && supports_keyboard_enhancement().unwrap_or(false) {
+            self.enhanced = execute!(
+                stdout(),
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            ).is_ok();
+        }
+        self.shared.stop.store(false, Ordering::SeqCst);
+        let shared = Arc::clone(&self.shared);
+        let hold = self.matrix_hold;
+        let enhanced = self.enhanced;
+        self.reader = Some(std::thread::spawn(move || read_keys(&shared, hold, enhanced)));
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            self.shared.stop.store(true, Ordering::SeqCst);
+            // wake the reader if it is paused
+            let pause = self.shared.pause.lock().unwrap();
+            self.shared.pause_changed.notify_all();
+            drop(pause);
+            let _ = reader.join();
+            restore_terminal(self.enhanced);
+            self.enhanced = false;
+        }
+    }
+
+    // Hands the terminal back in cooked mode, e.g. for INPUT's line editing.
+    // Returns once the reader has finished its current poll and read and
+    // stopped, so it cannot take the keys typed for the line.
+    pub fn suspend(&self) {
+        if self.is_active() {
+            let mut pause = self.shared.pause.lock().unwrap();
+            pause.requested = true;
+            while !pause.acknowledged {
+                pause = self.shared.pause_changed.wait(pause).unwrap();
+            }
+            drop(pause);
+            disable_raw_mode().unwrap();
+        }
+    }
+
+    // Gives the terminal back to the reader once the line has been read.
+    pub fn resume(&self) {
+        if self.is_active() {
+            enable_raw_mode().unwrap();
+            self.shared.pause.lock().unwrap().requested = false;
+            self.shared.pause_changed.notify_all();
+        }
+    }
+
+    pub fn pop(&self) -> Option<KeyPress> {
+        self.shared.queue.lock().unwrap().pop_front()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.shared.queue.lock().unwrap().len()
+    }
+
+    pub fn matrix(&self) -> [u8; KEY_MATRIX_SIZE] {
+        *self.shared.matrix.lock().unwrap()
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY, OverflowPolicy::DropNewest)
+    }
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn restore_terminal(enhanced: bool) {
+    if enhanced {
+        let _ = execute!(stdout(), PopKeyboardEnhancementFlags);
+    }
+    let _ = disable_raw_mode();
+}
+
+fn matrix_code(code: KeyCode) -> u8 {
+    key_code(code).to_ascii_lowercase()
+}
+
+fn read_keys(shared: &Shared, hold: Option<Duration>, enhanced: bool) {
+    let mut held: [Option<Instant>; 256] = [None; 256];
+    // keys the terminal has sent a release for; the rest fall back to `hold`
+    let mut releases = [false; 256];
+    while !shared.stop.load(Ordering::SeqCst) {
+        let mut pause = shared.pause.lock().unwrap();
+        if pause.requested {
+            pause.acknowledged = true;
+            shared.pause_changed.notify_all();
+            while pause.requested && !shared.stop.load(Ordering::SeqCst) {
+                pause = shared.pause_changed.wait(pause).unwrap();
+            }
+            pause.acknowledged = false;
+            continue;
+        }
+        drop(pause);
+        if poll(Duration::from_millis(10)).unwrap_or(false)
+            && let Ok(Event::Key(KeyEvent { code, modifiers, kind, .. })) = read()
+        {
+            handle_key(shared, code, modifiers, kind, enhanced, &mut held, &mut releases);
+        }
+        if let Some(hold) = hold {
+            *shared.matrix.lock().unwrap() = matrix_bits(&mut held, &releases, hold);
+        }
+    }
+}
+
+// The matrix for the keys held now, forgetting those pressed longer than
+// `hold` ago whose release the terminal does not report.
+fn matrix_bits(held: &mut [Option<Instant>; 256], releases: &[bool; 256], hold: Duration) -> [u8; KEY_MATRIX_SIZE] {
+    let mut bits = [0u8; KEY_MATRIX_SIZE];
+    for (code, since) in held.iter_mut().enumerate() {
+        if let Some(time) = since {
+            if !releases[code] && time.elapsed() > hold {
+                *since = None;
+            } else {
+                bits[code / 8] |= 1 << (code % 8);
+            }
+        }
+    }
+    bits
+}
+
+fn handle_key(
+    shared: &Shared,
+    code: KeyCode,
+    modifiers: KeyModifiers,
+    kind: KeyEventKind,
+    enhanced: bool,
+    held: &mut [Option<Instant>; 256],
+    releases: &mut [bool; 256],
+) {
+    let matrix_index = matrix_code(code) as usize;
+    if kind == KeyEventKind::Release {
+        held[matrix_index] = None;
+        releases[matrix_index] = true;
+        return;
+    }
+    if matrix_index != 0 {
+        held[matrix_index] = Some(Instant::now());
+    }
+    // raw mode swallows SIGINT, so Ctrl+C has to be handled here
+    if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+        restore_terminal(enhanced);
+        std::process::exit(130);
+    }
+    let key = KeyPress { code: key_code(code), mods: modifier_bits(code, modifiers) };
+    if key.code != 0 {
+        shared.push(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_codes() {
+        assert_eq!(key_code(KeyCode::Char('a')), b'a');
+        assert_eq!(key_code(KeyCode::Char('A')), b'A');
+        assert_eq!(key_code(KeyCode::Enter), b'\n');
+        assert_eq!(key_code(KeyCode::BackTab), b'\t');
+        assert_eq!(key_code(KeyCode::Delete), 127);
+        assert_eq!(key_code(KeyCode::Up), KEY_UP);
+        assert_eq!(key_code(KeyCode::Insert), KEY_INSERT);
+        assert_eq!(key_code(KeyCode::F(1)), KEY_F1);
+        assert_eq!(key_code(KeyCode::F(12)), 152);
+        // keys INKEY cannot report
+        assert_eq!(key_code(KeyCode::F(13)), 0);
+        assert_eq!(key_code(KeyCode::Char('é')), 0);
+        assert_eq!(key_code(KeyCode::CapsLock), 0);
+    }
+
+    #[test]
+    fn modifiers() {
+        assert_eq!(modifier_bits(KeyCode::Char('a'), KeyModifiers::NONE), 0);
+        assert_eq!(modifier_bits(KeyCode::Char('A'), KeyModifiers::SHIFT), MOD_SHIFT);
+        assert_eq!(modifier_bits(KeyCode::Up, KeyModifiers::CONTROL | KeyModifiers::ALT), MOD_CTRL | MOD_ALT);
+        assert_eq!(modifier_bits(KeyCode::BackTab, KeyModifiers::NONE), MOD_SHIFT);
+        assert_eq!(modifier_bits(KeyCode::Char('x'), KeyModifiers::SUPER), 0);
+    }
+
+    fn queued(policy: OverflowPolicy, codes: &[u8]) -> Vec<u8> {
+        let keyboard = Keyboard::new(2, policy);
+        for &code in codes {
+            keyboard.shared.push(KeyPress { code, mods: 0 });
+        }
+        assert_eq!(keyboard.pending(), codes.len().min(2));
+        std::iter::from_fn(|| keyboard.pop()).map(|key| key.code).collect()
+    }
+
+    #[test]
+    fn queue_overflow() {
+        assert_eq!(queued(OverflowPolicy::DropNewest, b"ab"), b"ab");
+        assert_eq!(queued(OverflowPolicy::DropNewest, b"abc"), b"ab");
+        assert_eq!(queued(OverflowPolicy::DropOldest, b"abc"), b"bc");
+        assert_eq!(OverflowPolicy::parse("drop-oldest"), Some(OverflowPolicy::DropOldest));
+        assert_eq!(OverflowPolicy::parse("drop-all"), None);
+    }
+
+    #[test]
+    fn matrix_layout() {
+        let keyboard = Keyboard::default();
+        let shared = &keyboard.shared;
+        let mut held = [None; 256];
+        let mut releases = [false; 256];
+        let press = |code, kind, held: &mut _, releases: &mut _| {
+            handle_key(shared, code, KeyModifiers::NONE, kind, false, held, releases);
+        };
+        press(KeyCode::Char('A'), KeyEventKind::Press, &mut held, &mut releases);
+        press(KeyCode::Left, KeyEventKind::Press, &mut held, &mut releases);
+        let bits = matrix_bits(&mut held, &releases, Duration::from_secs(60));
+        // 'a' is 97 = 12 * 8 + 1, Left is 130 = 16 * 8 + 2
+        let mut expected = [0; KEY_MATRIX_SIZE];
+        expected[12] = 1 << 1;
+        expected[16] = 1 << 2;
+        assert_eq!(bits, expected);
+        press(KeyCode::Char('a'), KeyEventKind::Release, &mut held, &mut releases);
+        expected[12] = 0;
+        assert_eq!(matrix_bits(&mut held, &releases, Duration::from_secs(60)), expected);
+    }
+
+    #[test]
+    fn held_keys_expire_without_releases() {
+        let mut held = [None; 256];
+        let mut releases = [false; 256];
+        let long_ago = Instant::now() - Duration::from_secs(1);
+        held[b'x' as usize] = Some(long_ago);
+        held[b'y' as usize] = Some(long_ago);
+        // the terminal has sent a release for y before, so it stays until the next one
+        releases[b'y' as usize] = true;
+        let bits = matrix_bits(&mut held, &releases, Duration::from_millis(500));
+        assert_eq!(bits[15], 1 << 1);
+        assert!(held[b'x' as usize].is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::modules::error::AsmError;
+use crate::modules::lexer::{ join, operand_ranges, Operand, Span, Token, TokenKind };
+
+// `.struct` and `.array` describe how data is laid out in memory; both only
+// define named constants, e.g. `Star.y` for the offset of a field.
+
+// A constant made by a layout directive: name, value expression and where it
+// was declared.
+pub type Definition = (String, String, Span);
+
+// A `.struct Name { ... }` whose fields may continue on the following lines.
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<String>,
+    // offset of the next field, as an expression
+    offset: String,
+    pub span: Span,
+}
+
+impl StructDef {
+    // Starts a struct from `.struct Name {`; returns it with the rest of the line.
+    pub fn open(tokens: &[Token]) -> Result<(StructDef, &[Token]), AsmError> {
+        match tokens {
+            [_, name, brace, rest @ ..] if name.kind == TokenKind::Ident && brace.is("{") => {
+                if name.text.contains('.') {
+                    return Err(AsmError::new(name.span, format!("Invalid struct name: {}", name.text)));
+                }
+                let def = StructDef { name: name.text.clone(), fields: Vec::new(), offset: "0".to_string(), span: name.span };
+                Ok((def, rest))
+            }
+            _ => Err(AsmError::new(tokens[0].span, ".struct expects a name and { fields }")),
+        }
+    }
+
+    // Reads `field` or `field[N]` declarations up to a closing `}`; returns
+    // the offsets they define and whether the struct is complete.
+    pub fn fields(&mut self, tokens: &[Token]) -> Result<(Vec<Definition>, bool), AsmError> {
+        let end = tokens.iter().position(|token| token.is("}"));
+        if let Some(end) = end
+            && let Some(extra) = tokens.get(end + 1)
+        {
+            return Err(AsmError::new(extra.span, "Unexpected text after }"));
+        }
+        let fields = &tokens[..end.unwrap_or(tokens.len())];
+        let mut definitions = Vec::new();
+        for (start, stop) in operand_ranges(fields) {
+            let field = &fields[start..stop];
+            let name = &field[0];
+            if name.kind != TokenKind::Ident || name.text.contains('.') || name.text == "size" {
+                return Err(AsmError::new(name.span, format!("Invalid field name: {}", name.text)));
+            }
+            let size = match &field[1..] {
+                [] => "1".to_string(),
+                [open, inner @ .., close] if open.is("[") && close.is("]") && !inner.is_empty() => join(inner).text,
+                _ => return Err(AsmError::new(name.span, "A field is NAME or NAME[SIZE]")),
+            };
+            definitions.push((format!("{}.{}", self.name, name.text), self.offset.clone(), name.span));
+            self.fields.push(name.text.clone());
+            self.offset = format!("{} + ({})", self.offset, size);
+        }
+        if end.is_some() {
+            definitions.push((format!("{}.size", self.name), self.offset.clone(), self.span));
+        }
+        Ok((definitions, end.is_some()))
+    }
+}
+
+// An `.array NAME [TYPE] COUNT at ADDR` declaration.
+pub struct ArrayDef<'a> {
+    pub name: &'a Token,
+    pub element: Option<&'a Token>,
+    pub count: Operand,
+    pub addr: Operand,
+}
+
+impl ArrayDef<'_> {
+    pub fn parse(tokens: &[Token]) -> Result<ArrayDef<'_>, AsmError> {
+        let usage = || AsmError::new(tokens[0].span, ".array expects NAME [TYPE] COUNT at ADDRESS");
+        let at = tokens.iter().position(|token| token.kind == TokenKind::Ident && token.text == "at").ok_or_else(usage)?;
+        if at + 1 == tokens.len() {
+            return Err(usage());
+        }
+        let head = &tokens[1..at];
+        let groups = operand_ranges(head);
+        let (name, element, count) = match groups.as_slice() {
+            [(name, _), (start, end)] => (&head[*name], None, join(&head[*start..*end])),
+            [(name, _), (element, _), (start, end)] => (&head[*name], Some(&head[*element]), join(&head[*start..*end])),
+            _ => return Err(usage()),
+        };
+        if name.kind != TokenKind::Ident || name.text.contains('.') {
+            return Err(AsmError::new(name.span, format!("Invalid array name: {}", name.text)));
+        }
+        Ok(ArrayDef { name, element, count, addr: join(&tokens[at + 1..]) })
+    }
+
+    // `NAME`, `NAME.count`, `NAME.size`, then `NAME.i` for every element and
+    // `NAME.i.field` for every field of a struct element.
+    pub fn definitions(&self, count: usize, fields: &[String]) -> Vec<Definition> {
+        let name = &self.name.text;
+        let span = self.name.span;
+        let element_size = match self.element {
+            Some(element) => format!("{}.size", element.text),
+            None => "1".to_string(),
+        };
+        let mut definitions = vec![
+            (name.clone(), format!("({})", self.addr.text), span),
+            (format!("{}.count", name), count.to_string(), span),
+            (format!("{}.size", name), format!("{} * {}", count, element_size), span),
+        ];
+        for i in 0..count {
+            let base = format!("{} + {} * {}", name, i, element_size);
+            for field in fields {
+                let element = self.element.map(|element| element.text.as_str()).unwrap_or_default();
+                definitions.push((format!("{}.{}.{}", name, i, field), format!("{} + {}.{}", base, element, field), span));
+            }
+            definitions.push((format!("{}.{}", name, i), base, span));
+        }
+        definitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::lexer::statements;
+
+    fn tokens(line: &str) -> Vec<Token> {
+        statements(line, 1, 0).unwrap().remove(0)
+    }
+
+    fn defined(definitions: &[Definition]) -> Vec<(&str, &str)> {
+        definitions.iter().map(|(name, value, _)| (name.as_str(), value.as_str())).collect()
+    }
+
+    #[test]
+    fn struct_fields() {
+        let line = tokens(".struct Star { x, y[2]");
+        let (mut star, rest) = StructDef::open(&line).unwrap();
+        let (definitions, complete) = star.fields(rest).unwrap();
+        assert_eq!(defined(&definitions), [("Star.x", "0"), ("Star.y", "0 + (1)")]);
+        assert!(!complete);
+        // continued on the next line
+        let (definitions, complete) = star.fields(&tokens("dir }")).unwrap();
+        assert_eq!(defined(&definitions), [("Star.dir", "0 + (1) + (2)"), ("Star.size", "0 + (1) + (2) + (1)")]);
+        assert!(complete);
+        assert_eq!(star.fields, ["x", "y", "dir"]);
+        assert_eq!(star.fields(&tokens("size }")).unwrap_err().message, "Invalid field name: size");
+    }
+
+    #[test]
+    fn array_elements() {
+        let line = tokens(".array STARS Star 2 at 2");
+        let array = ArrayDef::parse(&line).unwrap();
+        assert_eq!(array.count.text, "2");
+        assert_eq!(defined(&array.definitions(2, &["x".to_string()])), [
+            ("STARS", "(2)"),
+            ("STARS.count", "2"),
+            ("STARS.size", "2 * Star.size"),
+            ("STARS.0.x", "STARS + 0 * Star.size + Star.x"),
+            ("STARS.0", "STARS + 0 * Star.size"),
+            ("STARS.1.x", "STARS + 1 * Star.size + Star.x"),
+            ("STARS.1", "STARS + 1 * Star.size"),
+        ]);
+        let line = tokens(".array BYTES 3 at 10");
+        assert_eq!(defined(&ArrayDef::parse(&line).unwrap().definitions(3, &[]))[2], ("BYTES.size", "3 * 1"));
+        let line = tokens(".array BYTES 3");
+        assert_eq!(ArrayDef::parse(&line).err().unwrap().message, ".array expects NAME [TYPE] COUNT at ADDRESS");
+    }
+}
@@ -0,0 +1,358 @@
+use std::ops::Range;
+use std::path::PathBuf;
+use crate::modules::error::AsmError;
+
+// A file read by the assembler; spans refer to it by its index.
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub text: String,
+    // the `.include` that pulled the file in
+    pub included_from: Option<Span>,
+}
+
+// Position of a token in the source: file index, 1-based line and column,
+// length in characters, and the macro expansion it was produced by, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+    pub expansion: Option<usize>,
+}
+
+impl Span {
+    // The span from the start of `self` to the end of `other`, or just `self`
+    // when the two do not share a line (e.g. a macro argument).
+    pub fn to(self, other: Span) -> Span {
+        if (self.file, self.line, self.expansion) != (other.file, other.line, other.expansion) {
+            return self;
+        }
+        Span { len: (other.col + other.len).saturating_sub(self.col), ..self }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Ident,
+    Directive,
+    Number,
+    Char,
+    Str,
+    Punct,
+    Separator,
+    Comment,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+    pub space_before: bool,
+}
+
+impl Token {
+    pub fn is(&self, text: &str) -> bool {
+        self.kind == TokenKind::Punct && self.text == text
+    }
+}
+
+// Longest first, so `<<` is not read as two `<`.
+const PUNCTS: &[&str] = &[
+    "<<", ">>", "==", "!=", "<=", ">=", "&&", "||",
+    ":", ",", "[", "]", "(", ")", "{", "}", "=", "+", "-", "*", "/", "%", "&", "|", "^", "~", "$", "<", ">", "!",
+];
+
+// Splits one source line into tokens, comments included.
+pub fn tokenize_line(line: &str, line_no: usize, file: usize) -> Result<Vec<Token>, AsmError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    let mut space_before = false;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            space_before = true;
+            pos += 1;
+            continue;
+        }
+        let start = pos;
+        let kind = if c == '/' && chars.get(pos + 1) == Some(&'/') {
+            pos = chars.len();
+            TokenKind::Comment
+        } else if c == ';' {
+            pos += 1;
+            TokenKind::Separator
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            pos = scan_word(&chars, pos + 1);
+            TokenKind::Ident
+        } else if c == '.' && chars.get(pos + 1).is_some_and(|c| c.is_ascii_alphabetic() || *c == '_') {
+            pos = scan_word(&chars, pos + 1);
+            TokenKind::Directive
+        } else if c.is_ascii_digit() {
+            while chars.get(pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                pos += 1;
+            }
+            TokenKind::Number
+        } else if c == '\'' || c == '"' {
+            pos = scan_quoted(&chars, pos, line_no, file)?;
+            if c == '\'' { TokenKind::Char } else { TokenKind::Str }
+        } else if let Some(punct) = PUNCTS.iter().find(|p| chars[pos..].starts_with(&p.chars().collect::<Vec<_>>())) {
+            pos += punct.len();
+            TokenKind::Punct
+        } else {
+            return Err(AsmError::new(
+                Span { file, line: line_no, col: pos + 1, len: 1, expansion: None },
+                format!("Unexpected character '{}'", c),
+            ));
+        };
+        tokens.push(Token {
+            kind,
+            text: chars[start..pos].iter().collect(),
+            span: Span { file, line: line_no, col: start + 1, len: pos - start, expansion: None },
+            space_before,
+        });
+        space_before = false;
+    }
+    Ok(tokens)
+}
+
+fn scan_word(chars: &[char], mut pos: usize) -> usize {
+    while chars.get(pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.') {
+        pos += 1;
+    }
+    pos
+}
+
+// Returns the position just after the closing quote.
+fn scan_quoted(chars: &[char], start: usize, line_no: usize, file: usize) -> Result<usize, AsmError> {
+    let quote = chars[start];
+    let mut pos = start + 1;
+    while pos < chars.len() {
+        match chars[pos] {
+            '\\' => pos += 2,
+            c if c == quote => return Ok(pos + 1),
+            _ => pos += 1,
+        }
+    }
+    Err(AsmError::new(
+        Span { file, line: line_no, col: start + 1, len: chars.len() - start, expansion: None },
+        if quote == '\'' { "Unterminated character literal" } else { "Unterminated string" },
+    ))
+}
+
+// Splits a line into statements on `;`, dropping comments.
+pub fn statements(line: &str, line_no: usize, file: usize) -> Result<Vec<Vec<Token>>, AsmError> {
+    let mut statements = vec![Vec::new()];
+    for token in tokenize_line(line, line_no, file)? {
+        match token.kind {
+            TokenKind::Comment => {}
+            TokenKind::Separator => statements.push(Vec::new()),
+            _ => statements.last_mut().unwrap().push(token),
+        }
+    }
+    statements.retain(|statement| !statement.is_empty());
+    Ok(statements)
+}
+
+#[derive(Debug, Clone)]
+pub struct Operand {
+    pub text: String,
+    pub span: Span,
+}
+
+// Groups tokens into operands. Commas between operands are optional:
+// without them, a space ends an operand unless an operator joins the two
+// sides, so `WIDTH - 1` is one operand while `1 -1` is two.
+pub fn operands(tokens: &[Token]) -> Vec<Operand> {
+    operand_ranges(tokens).into_iter().map(|(start, end)| join(&tokens[start..end])).collect()
+}
+
+// Start and end index in `tokens` of each operand.
+pub fn operand_ranges(tokens: &[Token]) -> Vec<(usize, usize)> {
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    let mut depth = 0usize;
+    let mut open = false;
+    for (i, token) in tokens.iter().enumerate() {
+        if depth == 0 && token.is(",") {
+            open = false;
+            continue;
+        }
+        let starts_new = !open
+            || (depth == 0
+                && token.space_before
+                && ends_operand(&tokens[i - 1])
+                && starts_operand(token, tokens.get(i + 1)));
+        if starts_new {
+            groups.push((i, i + 1));
+            open = true;
+        } else {
+            groups.last_mut().unwrap().1 = i + 1;
+        }
+        if token.is("[") || token.is("(") {
+            depth += 1;
+        } else if token.is("]") || token.is(")") {
+            depth = depth.saturating_sub(1);
+        }
+    }
+    groups
+}
+
+// One operand made of all of `tokens`, spaced as in the source.
+pub fn join(tokens: &[Token]) -> Operand {
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && token.space_before {
+            // as many spaces as there are when both tokens are on one line
+            let previous = tokens[i - 1].span;
+            let gap = if previous.to(token.span) == previous {
+                1
+            } else {
+                token.span.col.saturating_sub(previous.col + previous.len)
+            };
+            text.push_str(&" ".repeat(gap.max(1)));
+        }
+        text.push_str(&token.text);
+    }
+    Operand { text, span: tokens[0].span.to(tokens[tokens.len() - 1].span) }
+}
+
+impl Operand {
+    // The span of some characters of `text`, or of the whole operand if its
+    // text is not a single piece of one source line.
+    pub fn span_of(&self, range: Range<usize>) -> Span {
+        if self.span.len != self.text.chars().count() {
+            return self.span;
+        }
+        Span { col: self.span.col + range.start, len: range.len(), ..self.span }
+    }
+}
+
+fn ends_operand(token: &Token) -> bool {
+    !matches!(token.kind, TokenKind::Punct) || token.is("]") || token.is(")") || token.is("$")
+}
+
+fn starts_operand(token: &Token, next: Option<&Token>) -> bool {
+    match token.kind {
+        TokenKind::Punct => {
+            token.is("[") || token.is("(") || token.is("$") || token.is("~") || token.is("!")
+                // a sign stuck to what follows, as in `1 -1` or `PRINT A -N`
+                || ((token.is("-") || token.is("+")) && next.is_some_and(|n| !n.space_before))
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<Token> {
+        tokenize_line(line, 3, 1).unwrap()
+    }
+
+    fn span(col: usize, len: usize) -> Span {
+        Span { file: 1, line: 3, col, len, expansion: None }
+    }
+
+    fn operand_texts(line: &str) -> Vec<String> {
+        operands(&tokens(line)[1..]).into_iter().map(|operand| operand.text).collect()
+    }
+
+    #[test]
+    fn kinds_and_spans() {
+        let tokens = tokens("LOOP: MOV A [X+1] // go");
+        let found: Vec<(TokenKind, &str, Span, bool)> = tokens.iter()
+            .map(|token| (token.kind, token.text.as_str(), token.span, token.space_before))
+            .collect();
+        assert_eq!(found, vec![
+            (TokenKind::Ident, "LOOP", span(1, 4), false),
+            (TokenKind::Punct, ":", span(5, 1), false),
+            (TokenKind::Ident, "MOV", span(7, 3), true),
+            (TokenKind::Ident, "A", span(11, 1), true),
+            (TokenKind::Punct, "[", span(13, 1), true),
+            (TokenKind::Ident, "X", span(14, 1), false),
+            (TokenKind::Punct, "+", span(15, 1), false),
+            (TokenKind::Number, "1", span(16, 1), false),
+            (TokenKind::Punct, "]", span(17, 1), false),
+            (TokenKind::Comment, "// go", span(19, 5), true),
+        ]);
+    }
+
+    #[test]
+    fn literals_and_directives() {
+        let kinds: Vec<(TokenKind, String)> = tokens(r#".string "a;b\"" 'x' 0x1F .loop Star.x"#).into_iter()
+            .map(|token| (token.kind, token.text))
+            .collect();
+        assert_eq!(kinds, vec![
+            (TokenKind::Directive, ".string".to_string()),
+            (TokenKind::Str, r#""a;b\"""#.to_string()),
+            (TokenKind::Char, "'x'".to_string()),
+            (TokenKind::Number, "0x1F".to_string()),
+            (TokenKind::Directive, ".loop".to_string()),
+            (TokenKind::Ident, "Star.x".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn longest_punctuation_first() {
+        let texts: Vec<String> = tokens("1<<2<=3!=!4").into_iter().map(|token| token.text).collect();
+        assert_eq!(texts, ["1", "<<", "2", "<=", "3", "!=", "!", "4"]);
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let tokens = tokens("MOV A 'é' // é");
+        assert_eq!(tokens[2].span, span(7, 3));
+        assert_eq!(tokens[3].span, span(11, 4));
+    }
+
+    #[test]
+    fn errors() {
+        let err = tokenize_line("MOV A \"abc", 3, 1).unwrap_err();
+        assert_eq!((err.span, err.message.as_str()), (span(7, 4), "Unterminated string"));
+        let err = tokenize_line("MOV A 'b", 3, 1).unwrap_err();
+        assert_eq!((err.span, err.message.as_str()), (span(7, 2), "Unterminated character literal"));
+        let err = tokenize_line("MOV A @", 3, 1).unwrap_err();
+        assert_eq!((err.span, err.message.as_str()), (span(7, 1), "Unexpected character '@'"));
+    }
+
+    #[test]
+    fn statements_split_on_separators() {
+        let statements = statements("MOV A 1; PRINT A ;; // done", 3, 1).unwrap();
+        let texts: Vec<Vec<&str>> = statements.iter()
+            .map(|statement| statement.iter().map(|token| token.text.as_str()).collect())
+            .collect();
+        assert_eq!(texts, vec![vec!["MOV", "A", "1"], vec!["PRINT", "A"]]);
+    }
+
+    #[test]
+    fn operand_grouping() {
+        assert_eq!(operand_texts("MOV A WIDTH - 1"), ["A", "WIDTH - 1"]);
+        assert_eq!(operand_texts("DRAW 1 -1 X"), ["1", "-1", "X"]);
+        assert_eq!(operand_texts("PRINT A -N"), ["A", "-N"]);
+        assert_eq!(operand_texts("DRAW 1, 2, 3"), ["1", "2", "3"]);
+        assert_eq!(operand_texts("MOV A [B + 1]"), ["A", "[B + 1]"]);
+        assert_eq!(operand_texts("MOV A hi(T) ~0"), ["A", "hi(T)", "~0"]);
+        assert_eq!(operand_texts(".byte (1) (2)"), ["(1)", "(2)"]);
+    }
+
+    #[test]
+    fn joined_operands_keep_source_spacing() {
+        let tokens = tokens("MOV A X  /  -1");
+        let operand = join(&tokens[2..]);
+        assert_eq!(operand.text, "X  /  -1");
+        assert_eq!(operand.span, span(7, 8));
+        assert_eq!(operand.span_of(3..4), span(10, 1));
+    }
+
+    #[test]
+    fn span_of_whole_operand_across_lines() {
+        let mut tokens = tokens("X + 1");
+        tokens[2].span.line = 9;
+        let operand = join(&tokens);
+        assert_eq!(operand.text, "X + 1");
+        assert_eq!(operand.span_of(2..3), operand.span);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use crate::modules::vm::{ Instruction, Source, MemSrc };
+use crate::modules::object::Object;
+use crate::modules::program::{ DataBlock, Program, SourceInfo, Symbol, SymbolKind };
+
+// Combines object files into one program. The code of each module follows
+// the previous one's, so its jump targets move by the number of instructions
+// before it; operands that use another module's symbols or the module's own
+// labels are then filled in.
+// All problems are reported, not just the first.
+pub fn link(objects: &[(String, Object)]) -> Result<Program, Vec<String>> {
+    let mut errors = Vec::new();
+    let mut bases = Vec::new();
+    let mut base = 0;
+    for (_, object) in objects {
+        bases.push(base);
+        base += object.program.instructions.len();
+    }
+
+    // exported symbols with their final values, and the module of each
+    let mut globals: HashMap<&str, (i64, SymbolKind, &str)> = HashMap::new();
+    for ((file, object), &base) in objects.iter().zip(&bases) {
+        for name in &object.exports {
+            let Some(symbol) = object.program.source.symbols.iter().find(|symbol| symbol.name == *name) else {
+                errors.push(format!("{}: exported symbol {} has no value", file, name));
+                continue;
+            };
+            let value = relocate(symbol, base);
+            if let Some((_, _, first)) = globals.insert(name, (value, symbol.kind, file)) {
+                errors.push(format!("{} is defined in both {} and {}", name, first, file));
+            }
+        }
+    }
+
+    let mut instructions = Vec::with_capacity(base);
+    for ((file, object), &base) in objects.iter().zip(&bases) {
+        for name in &object.imports {
+            if !globals.contains_key(name.as_str()) {
+                errors.push(format!("{}: undefined symbol {}", file, name));
+            }
+        }
+        let mut code: Vec<Instruction> = object.program.instructions.iter()
+            .map(|instruction| match instruction.jump_target() {
+                Some(target) => instruction.with_jump_target(target + base),
+                None => *instruction,
+            })
+            .collect();
+        for relocation in &object.relocations {
+            let value = match relocation.symbol.as_str() {
+                "" => base as i64,
+                symbol => match globals.get(symbol) {
+                    Some(&(value, _, _)) => value,
+                    None => continue,
+                },
+            };
+            let instruction = &mut code[relocation.index];
+            // an addend read from a damaged or handmade file could overflow
+            let value = value.checked_add(relocation.addend)
+                .ok_or_else(|| format!("{} {:+} is out of range", relocation.symbol, relocation.addend));
+            match value.and_then(|value| patch(*instruction, relocation.operand, value)) {
+                Ok(patched) => *instruction = patched,
+                Err(e) => errors.push(format!("{}: instruction {} ({}): {}", file, relocation.index, instruction, e)),
+            }
+        }
+        instructions.extend(code);
+    }
+
+    let mut data: Vec<DataBlock> = Vec::new();
+    let mut owner: [Option<&str>; 256] = [None; 256];
+    for (file, object) in objects {
+        for block in &object.program.data {
+            let start = block.addr as usize;
+            let clash = (start..start + block.bytes.len()).find_map(|addr| owner[addr].map(|other| (addr, other)));
+            if let Some((addr, other)) = clash {
+                errors.push(format!("{}: data at address {} overlaps data from {}", file, addr, other));
+                continue;
+            }
+            owner[start..start + block.bytes.len()].fill(Some(file));
+            data.push(block.clone());
+        }
+    }
+    data.sort_by_key(|block| block.addr);
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Program { instructions, data, entry: 0, source: SourceInfo { locations: Vec::new(), symbols: symbols(objects, &bases), data: Vec::new() } })
+}
+
+// A label's value is an index within its module.
+fn relocate(symbol: &Symbol, base: usize) -> i64 {
+    match symbol.kind {
+        SymbolKind::Label => symbol.value + base as i64,
+        SymbolKind::Constant => symbol.value,
+    }
+}
+
+// The exported symbols, and the others where their name is used by only one
+// module, so that the program still disassembles to valid source.
+fn symbols(objects: &[(String, Object)], bases: &[usize]) -> Vec<Symbol> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for (_, object) in objects {
+        for symbol in &object.program.source.symbols {
+            *seen.entry(&symbol.name).or_default() += 1;
+        }
+    }
+    let mut symbols: Vec<Symbol> = objects.iter().zip(bases)
+        .flat_map(|((_, object), &base)| object.program.source.symbols.iter()
+            .filter(|symbol| seen[symbol.name.as_str()] == 1 || object.exports.contains(&symbol.name))
+            .map(move |symbol| Symbol { name: symbol.name.clone(), value: relocate(symbol, base), kind: symbol.kind }))
+        .collect();
+    symbols.sort_by(|a, b| (a.value, &a.name).cmp(&(b.value, &b.name)));
+    symbols
+}
+
+// Sets the operand at `position` (counted as written in the source) to `value`.
+fn patch(instruction: Instruction, position: u8, value: i64) -> Result<Instruction, String> {
+    let byte = || match value {
+        -128..=255 => Ok(value as u8),
+        _ => Err(format!("Value {} is out of range (-128 to 255)", value)),
+    };
+    let address = || match value {
+        0..=255 => Ok(value as u8),
+        _ => Err(format!("Address {} is out of range (0 to 255)", value)),
+    };
+    let word = || match value {
+        0..=65535 => Ok(value as usize),
+        _ => Err(format!("Value {} is out of range (0 to 65535)", value)),
+    };
+    let source = |src: Source| match src {
+        Source::Lit(_) => Ok(Source::Lit(byte()?)),
+        Source::Mem(MemSrc::Addr(_)) => Ok(Source::Mem(MemSrc::Addr(address()?))),
+        _ => Err("Operand is a register".to_string()),
+    };
+    Ok(match (instruction, position) {
+        (Instruction::MOV(reg, src), 1) => Instruction::MOV(reg, source(src)?),
+        (Instruction::ADD(reg, src), 1) => Instruction::ADD(reg, source(src)?),
+        (Instruction::SUB(reg, src), 1) => Instruction::SUB(reg, source(src)?),
+        (Instruction::MUL(reg, src), 1) => Instruction::MUL(reg, source(src)?),
+        (Instruction::DIV(reg, src), 1) => Instruction::DIV(reg, source(src)?),
+        (Instruction::MOD(reg, src), 1) => Instruction::MOD(reg, source(src)?),
+        (Instruction::CMP(reg, src), 1) => Instruction::CMP(reg, source(src)?),
+        (Instruction::STORE(reg, MemSrc::Addr(_)), 1) => Instruction::STORE(reg, MemSrc::Addr(address()?)),
+        (Instruction::DRAW(x, y, src), 0) => Instruction::DRAW(source(x)?, y, src),
+        (Instruction::DRAW(x, y, src), 1) => Instruction::DRAW(x, source(y)?, src),
+        (Instruction::DRAW(x, y, src), 2) => Instruction::DRAW(x, y, source(src)?),
+        (Instruction::JMP(_) | Instruction::JZ(_) | Instruction::JNZ(_) | Instruction::LOOP(..), 0) => {
+            instruction.with_jump_target(word()?)
+        }
+        (Instruction::SLP(_), 0) => Instruction::SLP(word()?),
+        (Instruction::FPS(_), 0) => Instruction::FPS(address()?),
+        _ => return Err(format!("Operand {} cannot use another module's symbol", position + 1)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::object::Relocation;
+    use crate::modules::parser::assemble_object;
+    use crate::modules::vm::Reg;
+
+    fn module(instructions: Vec<Instruction>, symbols: &[(&str, i64, SymbolKind)], exports: &[&str], imports: &[&str]) -> Object {
+        let mut program = Program { instructions, ..Program::default() };
+        program.source.symbols = symbols.iter()
+            .map(|&(name, value, kind)| Symbol { name: name.to_string(), value, kind })
+            .collect();
+        Object {
+            program,
+            exports: exports.iter().map(|name| name.to_string()).collect(),
+            imports: imports.iter().map(|name| name.to_string()).collect(),
+            relocations: Vec::new(),
+        }
+    }
+
+    fn uses(mut object: Object, index: usize, operand: u8, symbol: &str, addend: i64) -> Object {
+        object.relocations.push(Relocation { index, operand, symbol: symbol.to_string(), addend });
+        object
+    }
+
+    fn main_module() -> Object {
+        let code = vec![Instruction::MOV(Reg::A, Source::Lit(0)), Instruction::JMP(0), Instruction::JMP(0)];
+        let object = module(code, &[("START", 0, SymbolKind::Label)], &[], &["LIMIT", "PRINT_IT"]);
+        uses(uses(object, 0, 1, "LIMIT", 2), 1, 0, "PRINT_IT", 0)
+    }
+
+    fn library() -> Object {
+        let code = vec![Instruction::PRINT(Reg::A, true), Instruction::JNZ(0), Instruction::HALT];
+        let symbols = [("PRINT_IT", 0, SymbolKind::Label), ("LIMIT", 10, SymbolKind::Constant)];
+        module(code, &symbols, &["PRINT_IT", "LIMIT"], &[])
+    }
+
+    fn linked(objects: Vec<(&str, Object)>) -> Result<Program, Vec<String>> {
+        let objects: Vec<(String, Object)> = objects.into_iter().map(|(file, object)| (file.to_string(), object)).collect();
+        link(&objects)
+    }
+
+    fn code(program: &Program) -> Vec<String> {
+        program.instructions.iter().map(|instruction| instruction.to_string()).collect()
+    }
+
+    #[test]
+    fn moves_jumps_and_fills_in_symbols() {
+        let program = linked(vec![("main.o", main_module()), ("lib.o", library())]).unwrap();
+        let expected = [Instruction::MOV(Reg::A, Source::Lit(12)), Instruction::JMP(3), Instruction::JMP(0),
+            Instruction::PRINT(Reg::A, true), Instruction::JNZ(3), Instruction::HALT];
+        assert_eq!(code(&program), expected.iter().map(|instruction| instruction.to_string()).collect::<Vec<_>>());
+        let symbols: Vec<(&str, i64)> = program.source.symbols.iter().map(|symbol| (symbol.name.as_str(), symbol.value)).collect();
+        assert_eq!(symbols, [("START", 0), ("PRINT_IT", 3), ("LIMIT", 10)]);
+    }
+
+    #[test]
+    fn moves_label_values() {
+        let object = uses(module(vec![Instruction::MOV(Reg::A, Source::Lit(1))], &[], &[], &[]), 0, 1, "", 1);
+        let program = linked(vec![("lib.o", library()), ("main.o", object)]).unwrap();
+        assert_eq!(code(&program)[3], "MOV A 4");
+    }
+
+    #[test]
+    fn assembled_modules() {
+        let assemble = |name: &str, text: &str| {
+            let path = std::env::temp_dir().join(format!("e8bit-{}-{}", std::process::id(), name));
+            std::fs::write(&path, text).unwrap();
+            let object = assemble_object(path.to_str().unwrap(), &[], &[]);
+            std::fs::remove_file(path).unwrap();
+            object
+        };
+        let main = assemble("a.e8", ".extern F\nMOV A 1\nJMP F\n");
+        let other = assemble("b.e8", ".export F\nHALT\nF: MOV A F\nMOV B $\nPRINT A\nHALT\n");
+        let program = linked(vec![("a.o", main), ("b.o", other)]).unwrap();
+        assert_eq!(code(&program), ["MOV A 1", "JMP 3", "HALT", "MOV A 3", "MOV B 4", "PRINT A", "HALT"]);
+    }
+
+    #[test]
+    fn private_symbols_give_way_to_exports() {
+        let private = module(vec![Instruction::HALT, Instruction::HALT], &[("PRINT_IT", 1, SymbolKind::Label)], &[], &[]);
+        let program = linked(vec![("main.o", main_module()), ("lib.o", library()), ("other.o", private)]).unwrap();
+        let names: Vec<(&str, i64)> = program.source.symbols.iter().map(|symbol| (symbol.name.as_str(), symbol.value)).collect();
+        assert_eq!(names, [("START", 0), ("PRINT_IT", 3), ("LIMIT", 10)]);
+    }
+
+    #[test]
+    fn undefined_and_duplicate_symbols() {
+        let errors = linked(vec![("main.o", main_module())]).unwrap_err();
+        assert_eq!(errors, ["main.o: undefined symbol LIMIT", "main.o: undefined symbol PRINT_IT"]);
+        let errors = linked(vec![("main.o", main_module()), ("a.o", library()), ("b.o", library())]).unwrap_err();
+        assert_eq!(errors, ["PRINT_IT is defined in both a.o and b.o", "LIMIT is defined in both a.o and b.o"]);
+    }
+
+    #[test]
+    fn values_must_fit_the_operand() {
+        let object = uses(main_module(), 2, 0, "LIMIT", -11);
+        let errors = linked(vec![("main.o", object), ("lib.o", library())]).unwrap_err();
+        assert_eq!(errors, ["main.o: instruction 2 (JMP 0): Value -1 is out of range (0 to 65535)"]);
+        let mut object = main_module();
+        object.relocations[0].addend = 250;
+        let errors = linked(vec![("main.o", object), ("lib.o", library())]).unwrap_err();
+        assert_eq!(errors, ["main.o: instruction 0 (MOV A 0): Value 260 is out of range (-128 to 255)"]);
+        let mut object = main_module();
+        object.relocations[0].addend = i64::MAX;
+        let errors = linked(vec![("main.o", object), ("lib.o", library())]).unwrap_err();
+        assert_eq!(errors, [format!("main.o: instruction 0 (MOV A 0): LIMIT {:+} is out of range", i64::MAX)]);
+    }
+
+    #[test]
+    fn data_may_not_overlap() {
+        let mut first = module(vec![Instruction::HALT], &[], &[], &[]);
+        first.program.data.push(DataBlock { addr: 40, bytes: vec![1, 2, 3] });
+        let mut second = module(vec![Instruction::HALT], &[], &[], &[]);
+        second.program.data.push(DataBlock { addr: 10, bytes: vec![4] });
+        let program = linked(vec![("a.o", first.clone()), ("b.o", second.clone())]).unwrap();
+        assert_eq!(program.data.iter().map(|block| block.addr).collect::<Vec<_>>(), [10, 40]);
+        second.program.data.push(DataBlock { addr: 38, bytes: vec![5, 6, 7] });
+        let errors = linked(vec![("a.o", first), ("b.o", second)]).unwrap_err();
+        assert_eq!(errors, ["b.o: data at address 40 overlaps data from a.o"]);
+    }
+}
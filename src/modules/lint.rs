@@ -0,0 +1,293 @@
+use std::fmt;
+use crate::modules::keyboard::KEY_MATRIX_ADDR;
+use crate::modules::program::Program;
+use crate::modules::vm::{ Instruction, MemSrc, Reg, Source };
+
+// `e8bit lint`: problems that can be found without running the program.
+//   errors:   jumps past the end of the program, division by zero
+//   warnings: unreachable code, LOOPs whose counter never changes, reads of
+//             registers and memory that nothing writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+pub struct Diagnostic {
+    pub severity: Severity,
+    // the instruction the problem is at
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+const REGS: [Reg; 5] = [Reg::A, Reg::B, Reg::C, Reg::D, Reg::E];
+
+// Every diagnostic, in program order. With `key_matrix`, the program is
+// meant to run with --key-matrix, which fills in the top of memory.
+pub fn lint(program: &Program, key_matrix: bool) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let code = &program.instructions;
+    let mut report = |severity, index, message: String| diagnostics.push(Diagnostic { severity, index, message });
+
+    for (index, instruction) in code.iter().enumerate() {
+        if let Some(target) = instruction.jump_target()
+            && target > code.len()
+        {
+            report(Severity::Error, index, format!(
+                "Jump to instruction {}, past the end of the program ({} instructions); the program would stop here",
+                target, code.len(),
+            ));
+        }
+        if let Instruction::DIV(_, Source::Lit(0)) | Instruction::MOD(_, Source::Lit(0)) = instruction {
+            report(Severity::Error, index, "Division by zero".to_string());
+        }
+    }
+
+    let reachable = reachable(program);
+    let mut index = 0;
+    while index < code.len() {
+        if reachable[index] {
+            index += 1;
+            continue;
+        }
+        let end = (index..code.len()).find(|&i| reachable[i]).unwrap_or(code.len());
+        let count = end - index;
+        report(Severity::Warning, index, format!(
+            "Unreachable code ({} instruction{})",
+            count, if count == 1 { "" } else { "s" },
+        ));
+        index = end;
+    }
+
+    for (index, instruction) in code.iter().enumerate() {
+        if let Instruction::LOOP(target, reg) = *instruction
+            && target <= index
+            && !code[target..index].iter().any(|body| writes(body) == Some(reg))
+        {
+            report(Severity::Warning, index, format!(
+                "LOOP counter {} is not changed in the loop, so it never ends unless {} is 0 when it starts",
+                reg, reg,
+            ));
+        }
+    }
+
+    for reg in REGS {
+        if code.iter().any(|instruction| writes(instruction) == Some(reg)) {
+            continue;
+        }
+        if let Some(index) = code.iter().position(|instruction| reads(instruction).contains(&reg)) {
+            report(Severity::Warning, index, format!("Reads {}, which nothing writes, so it is always 0", reg));
+        }
+    }
+
+    // a STORE through a register could write anywhere
+    let indirect = code.iter().any(|instruction| matches!(instruction, Instruction::STORE(_, MemSrc::Reg(_))));
+    if !indirect {
+        let mut written = [false; 256];
+        for block in &program.data {
+            let start = block.addr as usize;
+            written[start..start + block.bytes.len()].fill(true);
+        }
+        if key_matrix {
+            written[KEY_MATRIX_ADDR..].fill(true);
+        }
+        for instruction in code {
+            if let Instruction::STORE(_, MemSrc::Addr(addr)) = instruction {
+                written[*addr as usize] = true;
+            }
+        }
+        for (index, instruction) in code.iter().enumerate() {
+            for addr in memory_reads(instruction) {
+                if !written[addr as usize] {
+                    // once per address
+                    written[addr as usize] = true;
+                    report(Severity::Warning, index, format!(
+                        "Reads [{}], which no STORE or data directive writes, so it is always 0",
+                        addr,
+                    ));
+                }
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.index, diagnostic.severity));
+    diagnostics
+}
+
+// Which instructions can run, following jumps from the entry point.
+fn reachable(program: &Program) -> Vec<bool> {
+    let code = &program.instructions;
+    let mut reachable = vec![false; code.len()];
+    let mut pending = vec![program.entry];
+    while let Some(index) = pending.pop() {
+        if index >= code.len() || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        match code[index] {
+            Instruction::HALT => {}
+            Instruction::JMP(target) => pending.push(target),
+            Instruction::JZ(target) | Instruction::JNZ(target) | Instruction::LOOP(target, _) => {
+                pending.push(target);
+                pending.push(index + 1);
+            }
+            _ => pending.push(index + 1),
+        }
+    }
+    reachable
+}
+
+// The register the instruction sets, if any.
+fn writes(instruction: &Instruction) -> Option<Reg> {
+    match *instruction {
+        Instruction::MOV(reg, _) | Instruction::ADD(reg, _) | Instruction::SUB(reg, _)
+        | Instruction::MUL(reg, _) | Instruction::DIV(reg, _) | Instruction::MOD(reg, _)
+        | Instruction::MULH(reg, _, _) | Instruction::INPUT(reg) | Instruction::INKEY(reg)
+        | Instruction::KEYMOD(reg) | Instruction::KEYCOUNT(reg) | Instruction::RAND(reg)
+        | Instruction::FCOUNT(reg) => Some(reg),
+        _ => None,
+    }
+}
+
+// The registers whose value the instruction uses.
+fn reads(instruction: &Instruction) -> Vec<Reg> {
+    let source = |src: Source| match src {
+        Source::Reg(reg) | Source::Mem(MemSrc::Reg(reg)) => Some(reg),
+        _ => None,
+    };
+    let mut regs = Vec::new();
+    match *instruction {
+        Instruction::MOV(_, src) => regs.extend(source(src)),
+        Instruction::ADD(reg, src) | Instruction::SUB(reg, src) | Instruction::MUL(reg, src)
+        | Instruction::DIV(reg, src) | Instruction::MOD(reg, src) | Instruction::CMP(reg, src) => {
+            regs.push(reg);
+            regs.extend(source(src));
+        }
+        Instruction::STORE(reg, mem) => {
+            regs.push(reg);
+            regs.extend(source(Source::Mem(mem)));
+        }
+        Instruction::MULH(_, a, b) => regs.extend([a, b]),
+        Instruction::LOOP(_, reg) | Instruction::PRINT(reg, _) | Instruction::PRINTCH(reg, _) => regs.push(reg),
+        Instruction::DRAW(x, y, src) => regs.extend([x, y, src].into_iter().filter_map(source)),
+        _ => {}
+    }
+    regs
+}
+
+// The fixed memory addresses the instruction reads.
+fn memory_reads(instruction: &Instruction) -> Vec<u8> {
+    let sources = match *instruction {
+        Instruction::MOV(_, src) | Instruction::ADD(_, src) | Instruction::SUB(_, src)
+        | Instruction::MUL(_, src) | Instruction::DIV(_, src) | Instruction::MOD(_, src)
+        | Instruction::CMP(_, src) => vec![src],
+        Instruction::DRAW(x, y, src) => vec![x, y, src],
+        _ => Vec::new(),
+    };
+    sources.into_iter()
+        .filter_map(|src| match src {
+            Source::Mem(MemSrc::Addr(addr)) => Some(addr),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::program::DataBlock;
+    use Instruction::*;
+
+    fn found(instructions: Vec<Instruction>) -> Vec<(Severity, usize, String)> {
+        found_in(Program { instructions, ..Program::default() })
+    }
+
+    fn found_in(program: Program) -> Vec<(Severity, usize, String)> {
+        lint(&program, false).into_iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.index, diagnostic.message))
+            .collect()
+    }
+
+    fn lit(value: u8) -> Source {
+        Source::Lit(value)
+    }
+
+    fn addr(addr: u8) -> Source {
+        Source::Mem(MemSrc::Addr(addr))
+    }
+
+    #[test]
+    fn clean_program() {
+        assert!(found(vec![MOV(Reg::A, lit(3)), PRINT(Reg::A, true), SUB(Reg::A, lit(1)), JNZ(1), HALT]).is_empty());
+    }
+
+    #[test]
+    fn jumps_past_the_end() {
+        // jumping to just after the last instruction stops the program
+        assert!(found(vec![JMP(1)]).is_empty());
+        let expected = "Jump to instruction 3, past the end of the program (2 instructions); the program would stop here";
+        assert_eq!(found(vec![JZ(3), HALT]), [(Severity::Error, 0, expected.to_string())]);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert!(found(vec![MOV(Reg::A, lit(8)), DIV(Reg::A, lit(2)), MOD(Reg::A, lit(3))]).is_empty());
+        assert_eq!(found(vec![MOV(Reg::A, lit(8)), DIV(Reg::A, lit(0)), MOD(Reg::A, lit(0))]), [
+            (Severity::Error, 1, "Division by zero".to_string()),
+            (Severity::Error, 2, "Division by zero".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn unreachable_code() {
+        assert_eq!(found(vec![JMP(3), CLS, CLS, HALT, CLS]), [
+            (Severity::Warning, 1, "Unreachable code (2 instructions)".to_string()),
+            (Severity::Warning, 4, "Unreachable code (1 instruction)".to_string()),
+        ]);
+        let program = Program { instructions: vec![CLS, HALT], entry: 1, ..Program::default() };
+        assert_eq!(found_in(program), [(Severity::Warning, 0, "Unreachable code (1 instruction)".to_string())]);
+    }
+
+    #[test]
+    fn loop_counter_not_changed() {
+        assert!(found(vec![MOV(Reg::C, lit(4)), CLS, SUB(Reg::C, lit(1)), LOOP(1, Reg::C)]).is_empty());
+        let expected = "LOOP counter C is not changed in the loop, so it never ends unless C is 0 when it starts";
+        assert_eq!(found(vec![MOV(Reg::C, lit(4)), CLS, LOOP(1, Reg::C)]), [(Severity::Warning, 2, expected.to_string())]);
+    }
+
+    #[test]
+    fn registers_never_written() {
+        assert_eq!(found(vec![PRINT(Reg::B, true), MOV(Reg::A, Source::Reg(Reg::B)), PRINT(Reg::A, true)]), [
+            (Severity::Warning, 0, "Reads B, which nothing writes, so it is always 0".to_string()),
+        ]);
+        // a write anywhere counts, even after the read
+        assert!(found(vec![PRINT(Reg::B, true), INPUT(Reg::B)]).is_empty());
+    }
+
+    #[test]
+    fn memory_never_written() {
+        let expected = "Reads [20], which no STORE or data directive writes, so it is always 0";
+        assert_eq!(found(vec![MOV(Reg::A, addr(20)), ADD(Reg::A, addr(20))]), [(Severity::Warning, 0, expected.to_string())]);
+        assert!(found(vec![MOV(Reg::A, lit(1)), STORE(Reg::A, MemSrc::Addr(20)), MOV(Reg::A, addr(20))]).is_empty());
+        let program = Program {
+            instructions: vec![MOV(Reg::A, addr(21))],
+            data: vec![DataBlock { addr: 20, bytes: vec![1, 2] }],
+            ..Program::default()
+        };
+        assert!(found_in(program).is_empty());
+        let matrix = Program { instructions: vec![MOV(Reg::A, addr(KEY_MATRIX_ADDR as u8))], ..Program::default() };
+        assert_eq!(lint(&matrix, false).len(), 1);
+        assert!(lint(&matrix, true).is_empty());
+        // a STORE through a register could have written it
+        assert!(found(vec![MOV(Reg::A, lit(1)), STORE(Reg::A, MemSrc::Reg(Reg::A)), MOV(Reg::A, addr(20))]).is_empty());
+    }
+}
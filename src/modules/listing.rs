@@ -0,0 +1,101 @@
+use std::fmt::Write;
+use crate::modules::program::{ Program, SymbolKind };
+use crate::modules::bytecode::{ encode_instruction, instruction_addresses };
+
+// A listing of an assembled program: every instruction with its index, its
+// bytecode address and bytes (as loaded at address 0), the label its jump
+// goes to and the source line it came from, then the data and the symbol
+// table.
+pub fn listing(program: &Program, title: &str) -> String {
+    let mut out = String::new();
+    let source = &program.source;
+    let addresses = instruction_addresses(&program.instructions, 0);
+    writeln!(out, "e8bit listing of {}", title).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "{:>5}  {:4}  {:<12}  {:<20}  {:<24}  SOURCE", "INDEX", "ADDR", "BYTES", "INSTRUCTION", "TARGET").unwrap();
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        // jumps are encoded with byte addresses, like in von Neumann mode
+        let encoded = match instruction.jump_target() {
+            Some(target) => addresses.get(target).map(|&addr| instruction.with_jump_target(addr)),
+            None => Some(*instruction),
+        };
+        let mut bytes = Vec::new();
+        let bytes = match encoded.map(|encoded| encode_instruction(&encoded, &mut bytes)) {
+            Some(Ok(())) => bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" "),
+            _ => "??".to_string(),
+        };
+        let target = instruction.jump_target()
+            .map(|target| source.label_offset(target).unwrap_or_else(|| "?".to_string()))
+            .unwrap_or_default();
+        let location = source.location(index).map(|location| location.to_string()).unwrap_or_default();
+        writeln!(
+            out,
+            "{:>5}  {:04x}  {:<12}  {:<20}  {:<24}  {}",
+            index, addresses[index], bytes, instruction.to_string(), target, location
+        ).unwrap();
+    }
+    writeln!(out, "{:>5}  {:04x}  (end of program, {} bytes)", "", addresses[program.instructions.len()], addresses[program.instructions.len()]).unwrap();
+
+    if !program.data.is_empty() {
+        writeln!(out).unwrap();
+        writeln!(out, "DATA").unwrap();
+        for block in &program.data {
+            for (row, chunk) in block.bytes.chunks(16).enumerate() {
+                let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+                writeln!(out, "{:>5}  {}", block.addr as usize + row * 16, bytes.join(" ")).unwrap();
+            }
+        }
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "SYMBOLS").unwrap();
+    for symbol in &source.symbols {
+        let kind = match symbol.kind {
+            SymbolKind::Label => "label",
+            SymbolKind::Constant => "constant",
+        };
+        writeln!(out, "{:>5}  {:<8}  {}", symbol.value, kind, symbol.name).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::program::{ DataBlock, SourceLocation, Symbol };
+    use crate::modules::vm::{ Instruction, Reg, Source };
+
+    #[test]
+    fn lists_code_data_and_symbols() {
+        let mut program = Program {
+            instructions: vec![Instruction::MOV(Reg::A, Source::Lit(7)), Instruction::JNZ(0), Instruction::HALT],
+            data: vec![DataBlock { addr: 2, bytes: vec![36, 0] }],
+            ..Program::default()
+        };
+        let location = |line: usize, text: &str| SourceLocation { file: "t.e8".to_string(), line, text: text.to_string(), expanded_at: None };
+        program.source.locations = vec![location(1, "START: MOV A 7"), location(2, "JNZ START"), location(3, "HALT")];
+        program.source.symbols = vec![
+            Symbol { name: "START".to_string(), value: 0, kind: SymbolKind::Label },
+            Symbol { name: "SCORE".to_string(), value: 2, kind: SymbolKind::Constant },
+        ];
+        let listing = listing(&program, "t.e8");
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines, [
+            "e8bit listing of t.e8",
+            "",
+            "INDEX  ADDR  BYTES         INSTRUCTION           TARGET                    SOURCE",
+            "    0  0000  01 00 01 07   MOV A 7                                         t.e8:1: START: MOV A 7",
+            // the jump is encoded with the byte address of its target
+            "    1  0004  0b 00 00      JNZ 0                 START                     t.e8:2: JNZ START",
+            "    2  0007  00            HALT                                            t.e8:3: HALT",
+            "       0008  (end of program, 8 bytes)",
+            "",
+            "DATA",
+            "    2  24 00",
+            "",
+            "SYMBOLS",
+            "    0  label     START",
+            "    2  constant  SCORE",
+        ]);
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::io::{ BufRead, Write };
+use std::path::{ Path, PathBuf };
+use serde_json::{ json, Value };
+use crate::modules::index::{ index, DefinitionKind, SourceIndex };
+use crate::modules::lexer::{ tokenize_line, Span, TokenKind };
+use crate::modules::lint::{ lint, Severity };
+use crate::modules::parser::{ check_source, MNEMONICS };
+use crate::modules::program::Program;
+
+// `e8bit lsp`: a language server speaking JSON-RPC over stdin and stdout.
+// Documents are assembled on every change for diagnostics; definitions,
+// references and completions come from a `SourceIndex` of the document and
+// the files it includes.
+pub fn serve(include_dirs: &[String], defines: &[String], key_matrix: bool) {
+    let mut server = Server {
+        include_dirs: include_dirs.to_vec(),
+        defines: defines.to_vec(),
+        key_matrix,
+        documents: HashMap::new(),
+        programs: HashMap::new(),
+        shutdown: false,
+    };
+    let mut input = std::io::stdin().lock();
+    while let Some(message) = read_message(&mut input) {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        match message.get("id") {
+            // a response to a request of ours; we send none
+            _ if method.is_empty() => {}
+            Some(id) => {
+                let response = match server.request(method, params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+                };
+                write_message(&response);
+            }
+            None if method == "exit" => std::process::exit(if server.shutdown { 0 } else { 1 }),
+            None => server.notification(method, params),
+        }
+    }
+}
+
+// Short documentation of each instruction, for hover and completion.
+const DOCS: &[(&str, &str, &str)] = &[
+    ("MOV", "MOV REG VALUE", "Copies a value, a memory cell (`[ADDR]`, `[REG]`) or a register into `REG`."),
+    ("STORE", "STORE REG [ADDR]", "Stores `REG` in memory at `ADDR`, or at the address in a register (`[REG]`)."),
+    ("ADD", "ADD REG VALUE", "`REG = REG + VALUE`, wrapping at 256. Sets the zero flag."),
+    ("SUB", "SUB REG VALUE", "`REG = REG - VALUE`, wrapping below 0. Sets the zero flag."),
+    ("MUL", "MUL REG VALUE", "`REG = REG * VALUE`, keeping the low byte. Sets the zero flag."),
+    ("MULH", "MULH DEST REG REG", "`DEST` = high byte of the product of the two registers."),
+    ("DIV", "DIV REG VALUE", "`REG = REG / VALUE`, rounded down. Dividing by zero stops the program."),
+    ("MOD", "MOD REG VALUE", "`REG = REG % VALUE`, the remainder of the division."),
+    ("JMP", "JMP LABEL", "Jumps to `LABEL` (or an instruction index)."),
+    ("JZ", "JZ LABEL", "Jumps to `LABEL` if the zero flag is set: the last result was 0, or `CMP` found the values equal."),
+    ("JNZ", "JNZ LABEL", "Jumps to `LABEL` if the zero flag is not set."),
+    ("LOOP", "LOOP LABEL REG", "Jumps to `LABEL` while `REG` is not 0. Does not change `REG`."),
+    ("PRINT", "PRINT REG [-N]", "Prints the value of `REG` as a number, followed by a newline unless `-N` is given."),
+    ("PRINTCH", "PRINTCH REG [-N]", "Prints the character whose code is in `REG`, followed by a newline unless `-N` is given."),
+    ("INPUT", "INPUT REG", "Reads a number or character typed by the user into `REG`."),
+    ("INKEY", "INKEY REG", "Reads the next key press without waiting: its code, or 0 if no key was pressed."),
+    ("KEYMOD", "KEYMOD REG", "The modifiers held with the last key read by `INKEY`: 1 = Shift, 2 = Ctrl, 4 = Alt."),
+    ("KEYCOUNT", "KEYCOUNT REG", "The number of key presses waiting in the keyboard queue."),
+    ("DRAW", "DRAW X Y CHAR", "Draws `CHAR` at column `X`, row `Y` of the 80 by 25 screen."),
+    ("SLP", "SLP MS", "Pauses for `MS` milliseconds."),
+    ("CMP", "CMP REG VALUE", "Sets the zero flag if `REG` equals `VALUE`, clears it otherwise."),
+    ("RAND", "RAND REG", "Sets `REG` to a random value between 0 and 255."),
+    ("CLS", "CLS", "Clears the screen."),
+    ("CTS", "CTS", "Clears the terminal."),
+    ("RENDER", "RENDER", "Shows the screen in the terminal."),
+    ("FRAME", "FRAME", "Shows the screen, then waits for the next frame at the rate set by `FPS`."),
+    ("FPS", "FPS RATE", "Sets the frame rate of `FRAME` (0 = no waiting)."),
+    ("FCOUNT", "FCOUNT REG", "The number of frames shown so far, modulo 256."),
+    ("HALT", "HALT", "Stops the program."),
+];
+
+struct Server {
+    include_dirs: Vec<String>,
+    defines: Vec<String>,
+    // lint as for `run --key-matrix`
+    key_matrix: bool,
+    // open documents by URI
+    documents: HashMap<String, String>,
+    // the program of each document when it last assembled, for hover values
+    programs: HashMap<String, Program>,
+    shutdown: bool,
+}
+
+impl Server {
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "e8bit", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => Ok(self.hover(params).unwrap_or(Value::Null)),
+            "textDocument/definition" => Ok(self.definition(params).unwrap_or(Value::Null)),
+            "textDocument/references" => Ok(self.references(params).unwrap_or(json!([]))),
+            "textDocument/completion" => Ok(self.completion(params)),
+            _ => Err((-32601, format!("Unknown method: {}", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.clone(), text.to_string());
+                self.publish(&uri);
+            }
+            "textDocument/didChange" => {
+                // full sync: the last change holds the whole text
+                if let Some(text) = params["contentChanges"].as_array().and_then(|changes| changes.last()).and_then(|change| change["text"].as_str()) {
+                    self.documents.insert(uri.clone(), text.to_string());
+                    self.publish(&uri);
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.programs.remove(&uri);
+                write_message(&json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    // Assembles the document and sends its errors, or its lint warnings if it
+    // assembles.
+    fn publish(&mut self, uri: &str) {
+        let path = uri_to_path(uri);
+        let text = self.documents[uri].clone();
+        let mut diagnostics = Vec::new();
+        match check_source(&path, text.clone(), &self.include_dirs, &self.defines) {
+            Ok(object) => {
+                // programs using other modules' symbols only make sense linked
+                if object.imports.is_empty() {
+                    let file = path.display().to_string();
+                    for diagnostic in lint(&object.program, self.key_matrix) {
+                        let Some(location) = object.program.source.location(diagnostic.index) else { continue };
+                        if location.file != file {
+                            continue;
+                        }
+                        let line = text.lines().nth(location.line - 1).unwrap_or_default();
+                        let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+                        let span = Span { file: 0, line: location.line, col: indent + 1, len: line.chars().count() - indent, expansion: None };
+                        diagnostics.push(json!({
+                            "range": range(span, line),
+                            "severity": if diagnostic.severity == Severity::Error { 1 } else { 2 },
+                            "source": "e8bit lint",
+                            "message": diagnostic.message,
+                        }));
+                    }
+                }
+                self.programs.insert(uri.to_string(), object.program);
+            }
+            Err((err, files)) => {
+                let line_of = |span: Span| files.get(span.file).and_then(|file| file.text.lines().nth(span.line - 1)).unwrap_or_default();
+                // errors in included files are shown at the `.include` line
+                let mut span = err.span;
+                let mut message = err.message.clone();
+                while span.file != 0
+                    && let Some(from) = files.get(span.file).and_then(|file| file.included_from)
+                {
+                    message = format!("{}:{}: {}", files[span.file].path.display(), span.line, message);
+                    span = from;
+                }
+                let related: Vec<Value> = err.notes.iter()
+                    .filter_map(|(span, note)| {
+                        let file = files.get(span.file)?;
+                        Some(json!({
+                            "location": { "uri": path_to_uri(&file.path), "range": range(*span, line_of(*span)) },
+                            "message": note,
+                        }))
+                    })
+                    .collect();
+                diagnostics.push(json!({
+                    "range": range(span, line_of(span)),
+                    "severity": 1,
+                    "source": "e8bit",
+                    "message": message,
+                    "relatedInformation": related,
+                }));
+            }
+        }
+        write_message(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }));
+    }
+
+    // The index of the document, reading included files from the editor when
+    // they are open there.
+    fn index(&self, uri: &str) -> Option<SourceIndex> {
+        let text = self.documents.get(uri)?;
+        let load = |path: &Path| {
+            self.documents.get(&path_to_uri(path)).cloned().or_else(|| std::fs::read_to_string(path).ok())
+        };
+        Some(index(&uri_to_path(uri), text, &self.include_dirs, &load))
+    }
+
+    // The document, line (1-based) and column (1-based, in characters) of a
+    // `TextDocumentPositionParams`.
+    fn position(&self, params: &Value) -> Option<(String, usize, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?.to_string();
+        let line = params["position"]["line"].as_u64()? as usize;
+        let character = params["position"]["character"].as_u64()? as usize;
+        let text = self.documents.get(&uri)?.lines().nth(line).unwrap_or_default();
+        Some((uri, line + 1, from_utf16(text, character) + 1))
+    }
+
+    fn hover(&self, params: &Value) -> Option<Value> {
+        let (uri, line, col) = self.position(params)?;
+        let text = self.documents[&uri].lines().nth(line - 1)?;
+        let token = tokenize_line(text, line, 0).ok()?.into_iter()
+            .find(|token| (token.span.col..=token.span.col + token.span.len).contains(&col) && token.kind != TokenKind::Comment)?;
+        if let Some((_, syntax, doc)) = DOCS.iter().find(|(name, _, _)| *name == token.text) {
+            let value = format!("```\n{}\n```\n{}", syntax, doc);
+            return Some(json!({ "contents": { "kind": "markdown", "value": value }, "range": range(token.span, text) }));
+        }
+        let index = self.index(&uri)?;
+        let definition = &index.definitions[index.definition_at(0, line, col)?];
+        let kind = match definition.kind {
+            DefinitionKind::Label => "label",
+            DefinitionKind::Constant => "constant",
+            DefinitionKind::Macro => "macro",
+            DefinitionKind::Struct => "struct",
+            DefinitionKind::Field => "field",
+            DefinitionKind::Array => "array",
+            DefinitionKind::Extern => "external symbol",
+        };
+        let mut value = format!("{} `{}`", kind, definition.name);
+        // the value the assembler gave the name under the cursor, or the definition
+        let value_of = |name: &str| self.programs.get(&uri)?.source.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.value);
+        let named = if token.kind == TokenKind::Ident { value_of(&token.text) } else { None };
+        match named.or_else(|| value_of(&definition.symbol)) {
+            Some(number) if definition.kind == DefinitionKind::Label => value += &format!(": instruction {}", number),
+            Some(number) if named.is_some() && token.text != definition.name => value += &format!("; `{}` = {}", token.text, number),
+            Some(number) => value += &format!(" = {}", number),
+            None => {}
+        }
+        let (path, source) = &index.files[definition.span.file];
+        value += &format!("\n\nDefined at {}:{}: `{}`", path.display(), definition.span.line, source.lines().nth(definition.span.line - 1).unwrap_or_default().trim());
+        Some(json!({ "contents": { "kind": "markdown", "value": value }, "range": range(token.span, text) }))
+    }
+
+    fn definition(&self, params: &Value) -> Option<Value> {
+        let (uri, line, col) = self.position(params)?;
+        let index = self.index(&uri)?;
+        let definition = &index.definitions[index.definition_at(0, line, col)?];
+        Some(location(&index, definition.span))
+    }
+
+    fn references(&self, params: &Value) -> Option<Value> {
+        let (uri, line, col) = self.position(params)?;
+        let index = self.index(&uri)?;
+        let definition = index.definition_at(0, line, col)?;
+        let declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+        let mut spans: Vec<Span> = index.uses(definition).copied().collect();
+        if declaration {
+            spans.insert(0, index.definitions[definition].span);
+        }
+        Some(Value::Array(spans.into_iter().map(|span| location(&index, span)).collect()))
+    }
+
+    // Instructions, registers, and the names defined in the document and its
+    // includes; the editor filters them by what has been typed.
+    fn completion(&self, params: &Value) -> Value {
+        let mut items = Vec::new();
+        for name in MNEMONICS {
+            let (syntax, doc) = DOCS.iter().find(|(doc, _, _)| doc == name).map(|(_, syntax, doc)| (*syntax, *doc)).unwrap_or_default();
+            items.push(json!({ "label": name, "kind": 14, "detail": syntax, "documentation": doc }));
+        }
+        for reg in ["A", "B", "C", "D", "E"] {
+            items.push(json!({ "label": reg, "kind": 6, "detail": "register" }));
+        }
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        if let Some(index) = self.index(uri) {
+            let mut seen = std::collections::HashSet::new();
+            for definition in &index.definitions {
+                let (kind, detail) = match definition.kind {
+                    DefinitionKind::Label => (18, "label"),
+                    DefinitionKind::Constant => (21, "constant"),
+                    DefinitionKind::Macro => (3, "macro"),
+                    DefinitionKind::Struct => (22, "struct"),
+                    DefinitionKind::Field => (5, "field"),
+                    DefinitionKind::Array => (21, "array"),
+                    DefinitionKind::Extern => (21, "external symbol"),
+                };
+                if seen.insert(&definition.name) {
+                    items.push(json!({ "label": definition.name, "kind": kind, "detail": detail }));
+                }
+            }
+        }
+        Value::Array(items)
+    }
+}
+
+fn location(index: &SourceIndex, span: Span) -> Value {
+    let (path, text) = &index.files[span.file];
+    json!({ "uri": path_to_uri(path), "range": range(span, text.lines().nth(span.line - 1).unwrap_or_default()) })
+}
+
+// LSP positions count lines from 0 and columns in UTF-16 code units.
+fn range(span: Span, line: &str) -> Value {
+    let start = to_utf16(line, span.col - 1);
+    let end = to_utf16(line, span.col - 1 + span.len);
+    json!({
+        "start": { "line": span.line - 1, "character": start },
+        "end": { "line": span.line - 1, "character": end },
+    })
+}
+
+fn to_utf16(line: &str, chars: usize) -> usize {
+    line.chars().take(chars).map(char::len_utf16).sum()
+}
+
+fn from_utf16(line: &str, units: usize) -> usize {
+    let mut count = 0;
+    line.chars().take_while(|c| {
+        count += c.len_utf16();
+        count <= units
+    }).count()
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    let path = String::from_utf8_lossy(&bytes).into_owned();
+    // `/C:/dir` on Windows
+    match path.strip_prefix('/') {
+        Some(rest) if rest.as_bytes().get(1) == Some(&b':') => PathBuf::from(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let text = path.display().to_string().replace('\\', "/");
+    let mut uri = String::from(if text.starts_with('/') { "file://" } else { "file:///" });
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' | b':' => uri.push(byte as char),
+            _ => uri += &format!("%{:02X}", byte),
+        }
+    }
+    uri
+}
+
+// A message: headers, a blank line, then `Content-Length` bytes of JSON.
+// None at the end of the input.
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if input.read_line(&mut header).ok()? == 0 {
+                return None;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case("Content-Length")
+            {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let Some(length) = length else { continue };
+        let mut body = vec![0; length];
+        input.read_exact(&mut body).ok()?;
+        if let Ok(message) = serde_json::from_slice(&body) {
+            return Some(message);
+        }
+    }
+}
+
+fn write_message(message: &Value) {
+    let body = message.to_string();
+    let mut out = std::io::stdout().lock();
+    let _ = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = out.flush();
+}
@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use crate::modules::error::AsmError;
+use crate::modules::lexer::{ operand_ranges, Span, Token, TokenKind };
+
+// Macros may call other macros (or themselves) this many levels deep.
+pub const MAX_DEPTH: usize = 16;
+
+// A `.macro NAME params ... .endm` definition.
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Vec<Token>>,
+    pub span: Span,
+}
+
+// One macro call, kept so errors in the expanded code can point back at it.
+pub struct Expansion {
+    pub name: String,
+    pub call: Span,
+}
+
+impl Macro {
+    // Starts a definition from its `.macro NAME a, b` line.
+    pub fn define(tokens: &[Token]) -> Result<Macro, AsmError> {
+        let name = match tokens.get(1) {
+            Some(name) if name.kind == TokenKind::Ident => name,
+            _ => return Err(AsmError::new(tokens[0].span, ".macro expects a name")),
+        };
+        let mut params = Vec::new();
+        for (start, end) in operand_ranges(&tokens[2..]) {
+            let param = &tokens[2 + start];
+            if end - start != 1 || param.kind != TokenKind::Ident {
+                return Err(AsmError::new(param.span, format!("Invalid parameter for macro {}", name.text)));
+            }
+            if params.contains(&param.text) {
+                return Err(AsmError::new(param.span, format!("Parameter {} is listed twice", param.text)));
+            }
+            params.push(param.text.clone());
+        }
+        Ok(Macro { name: name.text.clone(), params, body: Vec::new(), span: name.span })
+    }
+
+    // The body with parameters replaced by the arguments of `call` and every
+    // label defined in the body, global or `.local`, renamed to
+    // `NAME.id.LABEL`, so each expansion gets its own labels.
+    pub fn expand(&self, call: &[Token], id: usize) -> Result<Vec<Vec<Token>>, AsmError> {
+        let args: Vec<&[Token]> = operand_ranges(&call[1..])
+            .into_iter()
+            .map(|(start, end)| &call[1 + start..1 + end])
+            .collect();
+        if args.len() != self.params.len() {
+            return Err(AsmError::new(
+                call[0].span,
+                format!("Macro {} expects {} arguments, got {}", self.name, self.params.len(), args.len()),
+            ));
+        }
+        let labels = self.labels();
+        let mut statements = Vec::new();
+        for statement in &self.body {
+            let mut tokens = Vec::new();
+            for token in statement {
+                if token.kind == TokenKind::Ident
+                    && let Some(index) = self.params.iter().position(|param| *param == token.text)
+                {
+                    let start = tokens.len();
+                    tokens.extend(args[index].iter().cloned());
+                    tokens[start].space_before = token.space_before;
+                    continue;
+                }
+                // `P.field` with P bound to a single name, e.g. a struct instance
+                if token.kind == TokenKind::Ident
+                    && let Some((prefix, rest)) = token.text.split_once('.')
+                    && let Some(index) = self.params.iter().position(|param| param == prefix)
+                    && let [arg] = args[index]
+                    && arg.kind == TokenKind::Ident
+                {
+                    let mut arg = arg.clone();
+                    arg.text = format!("{}.{}", arg.text, rest);
+                    arg.space_before = token.space_before;
+                    tokens.push(arg);
+                    continue;
+                }
+                let mut token = token.clone();
+                token.span.expansion = Some(id);
+                if matches!(token.kind, TokenKind::Ident | TokenKind::Directive) && labels.contains(token.text.as_str()) {
+                    token.text = format!("{}.{}.{}", self.name, id, token.text.trim_start_matches('.'));
+                    token.kind = TokenKind::Ident;
+                }
+                tokens.push(token);
+            }
+            statements.push(tokens);
+        }
+        Ok(statements)
+    }
+
+    fn labels(&self) -> HashSet<&str> {
+        let mut labels = HashSet::new();
+        for statement in &self.body {
+            let mut tokens = statement.as_slice();
+            while let [name, colon, rest @ ..] = tokens
+                && matches!(name.kind, TokenKind::Ident | TokenKind::Directive)
+                && colon.is(":")
+            {
+                labels.insert(name.text.as_str());
+                tokens = rest;
+            }
+        }
+        labels
+    }
+}
@@ -1,3 +1,21 @@
 pub mod vm;
 pub mod parser;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod keyboard;
+pub mod bytecode;
+pub mod program;
+pub mod expr;
+pub mod lexer;
+pub mod error;
+pub mod macros;
+pub mod layout;
+pub mod listing;
+pub mod container;
+pub mod disasm;
+pub mod object;
+pub mod linker;pub mod syntax;
+pub mod fmt;
+pub mod lint;
+pub mod cfg;
+pub mod index;
+pub mod lsp;
\ No newline at end of file
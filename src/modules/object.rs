@@ -0,0 +1,146 @@
+use crate::modules::container::{ header, open, push_name, push_u16, read_body, seal, write_body };
+use crate::modules::program::Program;
+
+// A separately assembled module, written by `e8bit asm -c` and combined into
+// a program by `e8bit link`.
+//
+// Same layout as a binary program (see `container.rs`) with magic "E8BO" and
+// no entry point; after the symbols come
+//   exports: count (2), then the names other modules may use
+//   imports: count (2), then the `.extern` names
+//   relocations: count (2), then per relocation the instruction index (2),
+//         operand (1), symbol name and addend (8)
+// Jump targets in the code are indices within the module. A relocation
+// with an empty symbol name is for a label of the module itself: the linker
+// adds the index of the module's first instruction.
+
+pub const MAGIC: &[u8; 4] = b"E8BO";
+
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub program: Program,
+    pub exports: Vec<String>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+// An operand that uses a symbol from another module, or a label's value:
+// the linker sets it to the symbol's value, or the module's first index,
+// plus `addend`.
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub index: usize,
+    // position among the instruction's operands, from 0
+    pub operand: u8,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write_object(object: &Object) -> Result<Vec<u8>, String> {
+    let mut out = header(MAGIC);
+    write_body(&mut out, &object.program)?;
+    for names in [&object.exports, &object.imports] {
+        push_u16(&mut out, names.len(), "Symbol count")?;
+        for name in names {
+            push_name(&mut out, name)?;
+        }
+    }
+    push_u16(&mut out, object.relocations.len(), "Relocation count")?;
+    for relocation in &object.relocations {
+        push_u16(&mut out, relocation.index, "Instruction index")?;
+        out.push(relocation.operand);
+        push_name(&mut out, &relocation.symbol)?;
+        out.extend(relocation.addend.to_le_bytes());
+    }
+    Ok(seal(out))
+}
+
+pub fn read_object(bytes: &[u8]) -> Result<Object, String> {
+    let mut reader = open(bytes, MAGIC, "an e8bit object file")?;
+    let program = read_body(&mut reader)?;
+    let mut lists = [Vec::new(), Vec::new()];
+    for names in &mut lists {
+        let count = reader.u16()?;
+        for _ in 0..count {
+            names.push(reader.name()?);
+        }
+    }
+    let [exports, imports] = lists;
+    let mut relocations = Vec::new();
+    let count = reader.u16()?;
+    for _ in 0..count {
+        let index = reader.u16()?;
+        if index >= program.instructions.len() {
+            return Err(format!("Relocation for instruction {} is past the end of the code", index));
+        }
+        let operand = reader.byte()?;
+        let symbol = reader.name()?;
+        let addend = i64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+        if !symbol.is_empty() && !imports.contains(&symbol) {
+            return Err(format!("Relocation uses {}, which is not imported", symbol));
+        }
+        relocations.push(Relocation { index, operand, symbol, addend });
+    }
+    reader.end()?;
+    Ok(Object { program, exports, imports, relocations })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::container::is_binary;
+    use crate::modules::container::tests::{ resealed, sample as program };
+
+    fn sample() -> Object {
+        Object {
+            program: program(),
+            exports: vec!["START".to_string()],
+            imports: vec!["COUNT".to_string(), "PRINT_IT".to_string()],
+            relocations: vec![
+                Relocation { index: 0, operand: 1, symbol: "COUNT".to_string(), addend: -3 },
+                Relocation { index: 4, operand: 0, symbol: "PRINT_IT".to_string(), addend: 0 },
+                Relocation { index: 6, operand: 2, symbol: String::new(), addend: 2 },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let object = sample();
+        let bytes = write_object(&object).unwrap();
+        assert!(is_object(&bytes) && !is_binary(&bytes));
+        let read = read_object(&bytes).unwrap();
+        assert_eq!(format!("{:?}", read.program.instructions), format!("{:?}", object.program.instructions));
+        assert_eq!(format!("{:?}", read.program.data), format!("{:?}", object.program.data));
+        assert_eq!(format!("{:?}", read.program.source.symbols), format!("{:?}", object.program.source.symbols));
+        assert_eq!((read.exports, read.imports), (object.exports, object.imports));
+        assert_eq!(format!("{:?}", read.relocations), format!("{:?}", object.relocations));
+    }
+
+    #[test]
+    fn damaged_files() {
+        let bytes = write_object(&sample()).unwrap();
+        let mut damaged = bytes.clone();
+        damaged[bytes.len() - 10] ^= 1;
+        assert_eq!(read_object(&damaged).unwrap_err(), "Checksum mismatch, the file is damaged");
+        assert_eq!(read_object(b"E8BT\x01\x01\0\0\0\0").unwrap_err(), "Not an e8bit object file");
+        let trailing = resealed(&bytes, |body| body.push(0));
+        assert_eq!(read_object(&trailing).unwrap_err(), "Unexpected bytes at the end of the file");
+    }
+
+    #[test]
+    fn relocations_are_checked() {
+        let mut past_end = sample();
+        past_end.relocations[1].index = 10;
+        let bytes = write_object(&past_end).unwrap();
+        assert_eq!(read_object(&bytes).unwrap_err(), "Relocation for instruction 10 is past the end of the code");
+        let mut not_imported = sample();
+        not_imported.imports.pop();
+        let bytes = write_object(&not_imported).unwrap();
+        assert_eq!(read_object(&bytes).unwrap_err(), "Relocation uses PRINT_IT, which is not imported");
+    }
+}
@@ -1,90 +1,85 @@
+use std::collections::{ HashMap, HashSet };
+use std::path::{ Path, PathBuf };
 use std::io::{stdout, Write};
 use crate::modules::utils::center_print;
 use crate::modules::vm::{Instruction, Reg, Source, MemSrc};
+use crate::modules::program::{ Program, DataBlock, DataLocation, SourceInfo, SourceLocation, Symbol, SymbolKind };
+use crate::modules::expr::{ evaluate, evaluate_at, parse_string, Scope };
+use crate::modules::lexer::{ statements, tokenize_line, operands, operand_ranges, join, SourceFile, Token, TokenKind, Operand, Span };
+use crate::modules::error::{ AsmError, report };
+use crate::modules::macros::{ Macro, Expansion, MAX_DEPTH };
+use crate::modules::layout::{ StructDef, ArrayDef };
+use crate::modules::object::{ Object, Relocation };
 
-fn parse_label_or_addr(addr: &str, label_map: &std::collections::HashMap<String, usize>) -> usize {
-    if let Ok(num) = addr.parse::<usize>() {
-        num
-    } else {
-        *label_map.get(addr).unwrap_or_else(|| panic!("Unknown label: {}", addr))
-    }
-}
-
-fn parse_instruction(parts: &[&str], label_map: &std::collections::HashMap<String, usize>) -> Option<Instruction> {
-    match parts {
-        ["MOV", reg, src] => Some(Instruction::MOV(parse_reg(reg), parse_source(src))),
-        ["PRINT", reg] => Some(Instruction::PRINT(parse_reg(reg), true)),
-        ["PRINT", reg, opt] if *opt == "-N" => Some(Instruction::PRINT(parse_reg(reg), false)),
-        ["PRINTCH", reg] => Some(Instruction::PRINTCH(parse_reg(reg), true)),
-        ["PRINTCH", reg, opt] if *opt == "-N" => Some(Instruction::PRINTCH(parse_reg(reg), false)),
-        ["ADD", reg, src] => Some(Instruction::ADD(parse_reg(reg), parse_source(src))),
-        ["SUB", reg, src] => Some(Instruction::SUB(parse_reg(reg), parse_source(src))),
-        ["MUL", reg, src] => Some(Instruction::MUL(parse_reg(reg), parse_source(src))),
-        ["MULH", dest, src1, src2] => Some(Instruction::MULH(parse_reg(dest), parse_reg(src1), parse_reg(src2))),
-        ["DIV", reg, src] => Some(Instruction::DIV(parse_reg(reg), parse_source(src))),
-        ["MOD", reg, src] => Some(Instruction::MOD(parse_reg(reg), parse_source(src))),
-        ["STORE", reg, src] => Some(Instruction::STORE(parse_reg(reg), parse_mem_src(src))),
-        ["JMP", addr] => Some(Instruction::JMP(parse_label_or_addr(addr, label_map))),
-        ["JZ", addr] => Some(Instruction::JZ(parse_label_or_addr(addr, label_map))),
-        ["JNZ", addr] => Some(Instruction::JNZ(parse_label_or_addr(addr, label_map))),
-        ["LOOP", addr, reg] => Some(Instruction::LOOP(parse_label_or_addr(addr, label_map), parse_reg(reg))),
-        ["INPUT", reg] => Some(Instruction::INPUT(parse_reg(reg))),
-        ["INKEY", reg] => Some(Instruction::INKEY(parse_reg(reg))),
-        ["DRAW", x, y, src] => Some(Instruction::DRAW(parse_source(x), parse_source(y), parse_source(src))),
-        ["SLP", duration] => Some(Instruction::SLP(duration.parse().unwrap())),
-        ["CMP", reg, src] => Some(Instruction::CMP(parse_reg(reg), parse_source(src))),
-        ["RAND", reg] => Some(Instruction::RAND(parse_reg(reg))),
-        ["RENDER"] => Some(Instruction::RENDER),
-        ["CLS"] => Some(Instruction::CLS),
-        ["CTS"] => Some(Instruction::CTS),
-        ["HALT"] => Some(Instruction::HALT),
-        _ => None,
-    }
+pub const MNEMONICS: &[&str] = &[
+    "MOV", "STORE", "ADD", "SUB", "MUL", "MULH", "DIV", "MOD", "JMP", "JZ", "JNZ", "LOOP",
+    "PRINT", "PRINTCH", "INPUT", "INKEY", "KEYMOD", "KEYCOUNT", "DRAW", "SLP", "CMP", "RAND",
+    "CLS", "CTS", "RENDER", "FRAME", "FPS", "FCOUNT", "HALT",
+];
+
+fn parse_instruction(statement: &Statement, ctx: &Context) -> Result<Instruction, AsmError> {
+    let name = statement.name.text.as_str();
+    let instruction = match (name, statement.operands.as_slice()) {
+        ("MOV", [reg, src]) => Instruction::MOV(parse_reg(reg)?, parse_source(src, ctx)?),
+        ("PRINT", [reg]) => Instruction::PRINT(parse_reg(reg)?, true),
+        ("PRINT", [reg, opt]) if opt.text == "-N" => Instruction::PRINT(parse_reg(reg)?, false),
+        ("PRINTCH", [reg]) => Instruction::PRINTCH(parse_reg(reg)?, true),
+        ("PRINTCH", [reg, opt]) if opt.text == "-N" => Instruction::PRINTCH(parse_reg(reg)?, false),
+        ("ADD", [reg, src]) => Instruction::ADD(parse_reg(reg)?, parse_source(src, ctx)?),
+        ("SUB", [reg, src]) => Instruction::SUB(parse_reg(reg)?, parse_source(src, ctx)?),
+        ("MUL", [reg, src]) => Instruction::MUL(parse_reg(reg)?, parse_source(src, ctx)?),
+        ("MULH", [dest, src1, src2]) => Instruction::MULH(parse_reg(dest)?, parse_reg(src1)?, parse_reg(src2)?),
+        ("DIV", [reg, src]) => Instruction::DIV(parse_reg(reg)?, parse_source(src, ctx)?),
+        ("MOD", [reg, src]) => Instruction::MOD(parse_reg(reg)?, parse_source(src, ctx)?),
+        ("STORE", [reg, src]) => Instruction::STORE(parse_reg(reg)?, parse_mem_src(src, ctx)?),
+        ("JMP", [addr]) => Instruction::JMP(ctx.target(addr)?),
+        ("JZ", [addr]) => Instruction::JZ(ctx.target(addr)?),
+        ("JNZ", [addr]) => Instruction::JNZ(ctx.target(addr)?),
+        ("LOOP", [addr, reg]) => Instruction::LOOP(ctx.target(addr)?, parse_reg(reg)?),
+        ("INPUT", [reg]) => Instruction::INPUT(parse_reg(reg)?),
+        ("INKEY", [reg]) => Instruction::INKEY(parse_reg(reg)?),
+        ("KEYMOD", [reg]) => Instruction::KEYMOD(parse_reg(reg)?),
+        ("KEYCOUNT", [reg]) => Instruction::KEYCOUNT(parse_reg(reg)?),
+        ("DRAW", [x, y, src]) => Instruction::DRAW(parse_source(x, ctx)?, parse_source(y, ctx)?, parse_source(src, ctx)?),
+        ("SLP", [duration]) => Instruction::SLP(ctx.eval(duration, 0, 65535, "Sleep duration")? as usize),
+        ("CMP", [reg, src]) => Instruction::CMP(parse_reg(reg)?, parse_source(src, ctx)?),
+        ("RAND", [reg]) => Instruction::RAND(parse_reg(reg)?),
+        ("RENDER", []) => Instruction::RENDER,
+        ("FRAME", []) => Instruction::FRAME,
+        ("FPS", [rate]) => Instruction::FPS(ctx.eval(rate, 0, 255, "Frame rate")? as u8),
+        ("FCOUNT", [reg]) => Instruction::FCOUNT(parse_reg(reg)?),
+        ("CLS", []) => Instruction::CLS,
+        ("CTS", []) => Instruction::CTS,
+        ("HALT", []) => Instruction::HALT,
+        _ if MNEMONICS.contains(&name) => {
+            return Err(AsmError::new(statement.span, format!("Wrong operands for {}", name)));
+        }
+        _ => return Err(AsmError::new(statement.name.span, format!("Unknown instruction: {}", name))),
+    };
+    Ok(instruction)
 }
 
-pub fn parse_program(file_path: Option<&str>) -> (Vec<Instruction>, bool) {
-    let mut debug_mode = false;
+pub fn parse_program(file_path: Option<&str>, include_dirs: &[String], defines: &[String]) -> (Program, bool) {
+    let mut assembler = Assembler::new(include_dirs, defines);
     if let Some(path) = file_path {
-        let content = std::fs::read_to_string(path).expect("Failed to read file");
-        let mut label_map = std::collections::HashMap::new();
-        let mut instructions_raw = Vec::new();
-        let mut pc = 0;
-        for line in content.lines() {
-            let line = line.trim();
-            let line = if let Some(comment_start) = line.find("//") {
-                &line[..comment_start].trim()
-            } else {
-                line
-            };
-            for segment in line.split(';') {
-                let segment = segment.trim();
-                if segment.is_empty() {
-                    continue;
-                }
-                if segment.ends_with(':') {
-                    let label = segment.trim_end_matches(':').to_string();
-                    label_map.insert(label, pc);
-                } else {
-                    instructions_raw.push(segment.to_string());
-                    pc += 1;
-                }
+        let text = std::fs::read_to_string(path).expect("Failed to read file");
+        match assembler.assemble(PathBuf::from(path), text) {
+            Ok(program) => (program, false),
+            Err(err) => {
+                report(&assembler.files, &assembler.explain(err));
+                std::process::exit(1);
             }
         }
-        let instructions = instructions_raw.iter().map(|segment| {
-            let parts: Vec<&str> = segment.split_whitespace().collect();
-            parse_instruction(&parts, &label_map)
-                .unwrap_or_else(|| panic!("Unknown instruction: {}", segment))
-        }).collect();
-        (instructions, debug_mode)
     } else {
+        // typed lines count as one file; includes are found from the current directory
+        let file = assembler.files.len();
+        assembler.files.push(SourceFile { path: PathBuf::from("<input>"), text: String::new(), included_from: None });
         center_print("IDLE MODE", 80);
         println!("No file provided. Enter instructions manually:");
         println!("{}", "-".repeat(82));
         println!("Type 'RUN' to stop the program.");
         println!("{}", "-".repeat(82));
-        let mut label_map = std::collections::HashMap::new();
-        let mut instructions_raw = Vec::new();
-        let mut pc = 0;
+        let mut line_no = 0;
         loop {
             let mut input = String::new();
             std::io::stdin()
@@ -94,36 +89,31 @@ pub fn parse_program(file_path: Option<&str>) -> (Vec<Instruction>, bool) {
             if line.eq_ignore_ascii_case("RUN") {
                 break;
             }
-
-            let line = if let Some(comment_start) = line.find("//") {
-                &line[..comment_start].trim()
-            } else {
-                line
-            };
-
-            for segment in line.split(';') {
-                let segment = segment.trim();
-                if segment.is_empty() {
-                    continue;
-                }
-                if segment.ends_with(':') {
-                    let label = segment.trim_end_matches(':').to_string();
-                    label_map.insert(label, pc);
-                } else {
-                    instructions_raw.push(segment.to_string());
-                    pc += 1;
-                }
+            line_no += 1;
+            assembler.files[file].text += &format!("{}\n", line);
+            if let Err(err) = assembler.add_line(line, line_no, file) {
+                println!("Error: {}", err);
             }
         }
-        let mut program = Vec::new();
-        for segment in instructions_raw {
-            let parts: Vec<&str> = segment.split_whitespace().collect();
-            if let Some(instruction) = parse_instruction(&parts, &label_map) {
-                program.push(instruction);
-            } else {
-                println!("Unknown instruction: {}", segment);
+        if let Err(err) = assembler.finish() {
+            println!("Error: {}", err);
+        }
+        let mut instructions = Vec::new();
+        let mut locations = Vec::new();
+        for (index, statement) in assembler.statements.iter().enumerate() {
+            match parse_instruction(statement, &assembler.symbols.at(index)) {
+                Ok(instruction) => {
+                    instructions.push(instruction);
+                    locations.push(assembler.locate(statement));
+                }
+                Err(err) => println!("Error: {}", err),
             }
         }
+        let (data, data_locations) = assembler.data().unwrap_or_else(|err| {
+            println!("Error: {}", err);
+            Default::default()
+        });
+        let source = SourceInfo { locations, symbols: assembler.symbol_table(), data: data_locations };
         println!("{}", "-".repeat(82));
         print!("Enable debug mode? (y/n): ");
         let _ = stdout().flush();
@@ -131,52 +121,1228 @@ pub fn parse_program(file_path: Option<&str>) -> (Vec<Instruction>, bool) {
         std::io::stdin()
             .read_line(&mut input)
             .expect("Failed to read line");
-        debug_mode = input.trim().eq_ignore_ascii_case("y");
-        (program, debug_mode)
+        let debug_mode = input.trim().eq_ignore_ascii_case("y");
+        (Program { instructions, data, entry: 0, source }, debug_mode)
     }
 }
 
-fn parse_reg(reg: &str) -> Reg {
-    match reg {
-        "A" => Reg::A,
-        "B" => Reg::B,
-        "C" => Reg::C,
-        "D" => Reg::D,
-        "E" => Reg::E,
-        _ => panic!("Unknown register: {}", reg),
-    }
-}
-
-fn parse_source(src: &str) -> Source {
-    if let Ok(lit) = src.parse::<u8>() {
-        Source::Lit(lit)
-    } else if src.starts_with("'") && src.ends_with("'") && src.len() == 3 {
-        let char_value = src.chars().nth(1).unwrap() as u8;
-        Source::Lit(char_value)
-        // chars
-    } else if src.starts_with("[") && src.ends_with(']') {
-        let inner = &src[1..src.len() - 1];
-        if let Ok(addr) = inner.parse::<u8>() {
-            Source::Mem(MemSrc::Addr(addr))
-        } else if inner.chars().all(|c| c.is_alphabetic()) {
-            Source::Mem(MemSrc::Reg(parse_reg(inner)))
+// Assembles one module of a program into an object file for `e8bit link`.
+pub fn assemble_object(path: &str, include_dirs: &[String], defines: &[String]) -> Object {
+    let mut assembler = Assembler::new(include_dirs, defines);
+    let text = std::fs::read_to_string(path).expect("Failed to read file");
+    assembler.assemble_object(PathBuf::from(path), text).unwrap_or_else(|err| {
+        report(&assembler.files, &assembler.explain(err));
+        std::process::exit(1);
+    })
+}
+
+// Assembles `text` as the file at `path` without printing anything, for the
+// language server. The file is assembled as an object file, so modules using
+// `.extern` can be checked too. On failure: the error with its notes, and
+// the files read.
+pub fn check_source(path: &Path, text: String, include_dirs: &[String], defines: &[String]) -> Result<Object, (AsmError, Vec<SourceFile>)> {
+    let mut assembler = Assembler::new(include_dirs, defines);
+    assembler.assemble_object(path.to_path_buf(), text).map_err(|err| {
+        let err = assembler.explain(err);
+        (err, std::mem::take(&mut assembler.files))
+    })
+}
+
+// An instruction or directive with its operands.
+struct Statement {
+    name: Token,
+    operands: Vec<Operand>,
+    span: Span,
+}
+
+impl Statement {
+    fn new(tokens: &[Token]) -> Self {
+        Statement {
+            name: tokens[0].clone(),
+            operands: operands(&tokens[1..]),
+            span: tokens[0].span.to(tokens[tokens.len() - 1].span),
+        }
+    }
+}
+
+// First pass: collects labels, constants, instruction statements and data
+// directives, expanding macros on the way.
+#[derive(Default)]
+struct Assembler {
+    symbols: Symbols,
+    statements: Vec<Statement>,
+    directives: Vec<Statement>,
+    macros: HashMap<String, Macro>,
+    // the macro whose body is being read, between `.macro` and `.endm`
+    recording: Option<Macro>,
+    expansions: Vec<Expansion>,
+    files: Vec<SourceFile>,
+    include_dirs: Vec<PathBuf>,
+    // files being read, innermost last, to catch include cycles
+    including: Vec<(PathBuf, usize)>,
+    // the last global label, owner of the `.local` labels that follow it
+    scope: Option<String>,
+    // open `.if`, `.while` and `.for` blocks, innermost last
+    blocks: Vec<OpenBlock>,
+    // numbers the labels generated for `.if`, `.while` and `.for`
+    generated: usize,
+    // the struct whose fields are being read, until its `}`
+    layout: Option<StructDef>,
+    // field names of each struct
+    structs: HashMap<String, Vec<String>>,
+    // `.array` names, checked to fit in memory
+    arrays: Vec<Token>,
+    // `.for` ranges known when assembling, checked to count up
+    for_ranges: Vec<(Span, Operand, Operand)>,
+    // `.export` names, for object files
+    exports: Vec<Token>,
+}
+
+struct OpenBlock {
+    block: Block,
+    span: Span,
+}
+
+enum Block {
+    // `.if`/`.ifdef`/`.ifndef` decided while assembling; `outer` tells
+    // whether the enclosing code is assembled at all
+    Assembly { outer: bool, value: bool, in_else: bool },
+    // `.if` on registers or memory, tested by the program
+    If { id: usize, in_else: bool },
+    While { id: usize },
+    For { id: usize, reg: Token, last: Vec<Token> },
+    // a `.while` or `.for` in code that is not assembled
+    Skipped(&'static str),
+}
+
+impl Block {
+    // The directive that closes the block.
+    fn end(&self) -> &'static str {
+        match self {
+            Block::Assembly { .. } | Block::If { .. } => ".endif",
+            Block::While { .. } => ".endwhile",
+            Block::For { .. } => ".endfor",
+            Block::Skipped(end) => end,
+        }
+    }
+}
+
+impl Assembler {
+    fn new(include_dirs: &[String], defines: &[String]) -> Self {
+        let mut assembler = Assembler {
+            include_dirs: include_dirs.iter().map(PathBuf::from).collect(),
+            ..Assembler::default()
+        };
+        if let Err(err) = assembler.define(defines) {
+            report(&assembler.files, &err);
+            std::process::exit(1);
+        }
+        assembler
+    }
+
+    fn assemble(&mut self, path: PathBuf, text: String) -> Result<Program, AsmError> {
+        self.add_file(path, text, None)?;
+        self.finish()?;
+        let instructions = self.statements.iter().enumerate()
+            .map(|(index, statement)| parse_instruction(statement, &self.symbols.at(index)))
+            .collect::<Result<_, _>>()?;
+        let (data, data_locations) = self.data()?;
+        let source = SourceInfo {
+            locations: self.statements.iter().map(|statement| self.locate(statement)).collect(),
+            symbols: self.symbol_table(),
+            data: data_locations,
+        };
+        Ok(Program { instructions, data, entry: 0, source })
+    }
+
+    // Like `assemble`, but `.extern` symbols may be used in instructions: each
+    // such operand becomes a relocation, filled in by the linker. So does
+    // every operand holding a label's value, which moves with the module.
+    fn assemble_object(&mut self, path: PathBuf, text: String) -> Result<Object, AsmError> {
+        self.add_file(path, text, None)?;
+        self.finish()?;
+        let mut instructions = Vec::new();
+        let mut relocations = Vec::new();
+        for (index, statement) in self.statements.iter().enumerate() {
+            for (position, operand) in statement.operands.iter().enumerate() {
+                let text = operand.text.strip_prefix('[').and_then(|text| text.strip_suffix(']')).unwrap_or(&operand.text);
+                let symbol = match self.symbols.externs_in(text, 0).as_slice() {
+                    [] => None,
+                    [symbol] => Some(symbol.clone()),
+                    _ => return Err(AsmError::new(operand.span, "An operand can use only one external symbol")),
+                };
+                let value = |externs, base| evaluate(text, &Context { externs: Some(externs), base, ..self.symbols.at(index) });
+                // registers, and operands parse_instruction reports
+                let Ok(addend) = value(0, 0) else { continue };
+                let moved = |externs, base| {
+                    value(externs, base).map(|value| value.wrapping_sub(addend)).map_err(|e| AsmError::new(operand.span, e))
+                };
+                // jump targets are moved by the linker anyway
+                let jump = position == 0 && matches!(statement.name.text.as_str(), "JMP" | "JZ" | "JNZ" | "LOOP");
+                let symbol = match symbol {
+                    Some(symbol) if moved(1, 0)? != 1 => {
+                        return Err(AsmError::new(
+                            operand.span,
+                            format!("External symbol {} can only have a number added to or subtracted from it", symbol),
+                        ));
+                    }
+                    Some(symbol) if moved(0, 256)? != 0 => {
+                        return Err(AsmError::new(operand.span, format!("External symbol {} cannot be used with a label", symbol)));
+                    }
+                    Some(symbol) => symbol,
+                    None if jump => continue,
+                    None => match (moved(0, 1)?, moved(0, 256)?) {
+                        (0, 0) => continue,
+                        (1, 256) => String::new(),
+                        _ => {
+                            return Err(AsmError::new(
+                                operand.span,
+                                "In an object file, a label can only have a number added to or subtracted from it",
+                            ));
+                        }
+                    },
+                };
+                relocations.push(Relocation { index, operand: position as u8, symbol, addend });
+            }
+            let ctx = Context { externs: Some(0), ..self.symbols.at(index) };
+            instructions.push(parse_instruction(statement, &ctx)?);
+        }
+        let (data, data_locations) = self.data()?;
+        let ctx = Context { symbols: &self.symbols, here: None, depth: 0, externs: None, base: 0 };
+        for name in &self.exports {
+            if self.symbols.labels.contains_key(&name.text) {
+                continue;
+            }
+            if !self.symbols.constants.contains_key(&name.text) {
+                return Err(AsmError::new(name.span, format!("Exported symbol {} is not defined in this module", name.text)));
+            }
+            ctx.eval(&Operand { text: name.text.clone(), span: name.span }, i64::MIN, i64::MAX, "Value")?;
+        }
+        let mut exports: Vec<String> = self.exports.iter().map(|name| name.text.clone()).collect();
+        exports.sort();
+        exports.dedup();
+        let mut imports: Vec<String> = self.symbols.externs.iter().cloned().collect();
+        imports.sort();
+        let source = SourceInfo {
+            locations: self.statements.iter().map(|statement| self.locate(statement)).collect(),
+            symbols: self.symbol_table(),
+            data: data_locations,
+        };
+        let program = Program { instructions, data, entry: 0, source };
+        Ok(Object { program, exports, imports, relocations })
+    }
+
+    fn add_file(&mut self, path: PathBuf, text: String, included_from: Option<Span>) -> Result<(), AsmError> {
+        let file = self.files.len();
+        let canonical = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if let Some(start) = self.including.iter().position(|(open, _)| *open == canonical) {
+            let mut chain: Vec<String> = self.including[start..].iter()
+                .map(|(_, index)| self.files[*index].path.display().to_string())
+                .collect();
+            chain.push(path.display().to_string());
+            return Err(AsmError::new(included_from.unwrap_or_default(), format!("Include cycle: {}", chain.join(" -> "))));
+        }
+        self.files.push(SourceFile { path, text: text.clone(), included_from });
+        self.including.push((canonical, file));
+        for (index, line) in text.lines().enumerate() {
+            self.add_line(line, index + 1, file)?;
+        }
+        if let Some(definition) = &self.recording
+            && definition.span.file == file
+        {
+            return Err(AsmError::new(definition.span, format!("Missing .endm for macro {}", definition.name)));
+        }
+        if let Some(definition) = &self.layout
+            && definition.span.file == file
+        {
+            return Err(AsmError::new(definition.span, format!("Missing }} for struct {}", definition.name)));
+        }
+        if let Some(open) = self.blocks.last()
+            && open.span.file == file
+        {
+            return Err(AsmError::new(open.span, format!("Missing {}", open.block.end())));
+        }
+        self.including.pop();
+        Ok(())
+    }
+
+    // `.include "path"`, looked up next to the including file, then in each
+    // include directory.
+    fn include(&mut self, tokens: &[Token]) -> Result<(), AsmError> {
+        let name = match tokens {
+            [_, name] if name.kind == TokenKind::Str => name,
+            _ => return Err(AsmError::new(tokens[0].span, ".include expects a quoted file name")),
+        };
+        let bytes = parse_string(&name.text).map_err(|e| AsmError::new(name.span, e))?;
+        let relative = String::from_utf8_lossy(&bytes).into_owned();
+        let here = self.files[name.span.file].path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let path = std::iter::once(here)
+            .chain(self.include_dirs.iter().cloned())
+            .map(|dir| dir.join(&relative))
+            .find(|path| path.is_file())
+            .ok_or_else(|| AsmError::new(name.span, format!("Cannot find include file {}", relative)))?;
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| AsmError::new(name.span, format!("Cannot read {}: {}", path.display(), e)))?;
+        self.add_file(path, text, Some(tokens[0].span.to(name.span)))
+    }
+
+    fn add_line(&mut self, line: &str, line_no: usize, file: usize) -> Result<(), AsmError> {
+        for tokens in statements(line, line_no, file)? {
+            if self.recording.is_some() {
+                self.record(tokens)?;
+            } else if self.layout.is_some() && self.assembling() {
+                self.struct_fields(&tokens)?;
+            } else {
+                self.add_statement(&tokens)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), AsmError> {
+        if let Some(definition) = self.recording.take() {
+            return Err(AsmError::new(definition.span, format!("Missing .endm for macro {}", definition.name)));
+        }
+        if let Some(definition) = self.layout.take() {
+            return Err(AsmError::new(definition.span, format!("Missing }} for struct {}", definition.name)));
+        }
+        if let Some(open) = self.blocks.pop() {
+            return Err(AsmError::new(open.span, format!("Missing {}", open.block.end())));
+        }
+        // the loop would wrap past 255 to get from FIRST down to LAST; values
+        // that do not evaluate are reported with the instructions using them
+        let ctx = Context { symbols: &self.symbols, here: None, depth: 0, externs: None, base: 0 };
+        for (span, first, last) in &self.for_ranges {
+            if let (Ok(first), Ok(last)) = (ctx.byte(first), ctx.byte(last))
+                && first > last
+            {
+                return Err(AsmError::new(*span, format!("FIRST ({}) is greater than LAST ({}), but .for only counts up", first, last)));
+            }
+        }
+        Ok(())
+    }
+
+    // `-D NAME=value` options, read as `NAME = value` lines of a file of their own.
+    fn define(&mut self, defines: &[String]) -> Result<(), AsmError> {
+        if defines.is_empty() {
+            return Ok(());
+        }
+        let text: String = defines.iter().map(|define| match define.split_once('=') {
+            Some((name, value)) => format!("{} = {}\n", name, value),
+            None => format!("{} = 1\n", define),
+        }).collect();
+        self.add_file(PathBuf::from("<command line>"), text, None)
+    }
+
+    fn record(&mut self, tokens: Vec<Token>) -> Result<(), AsmError> {
+        let first = &tokens[0];
+        if first.kind == TokenKind::Directive && first.text == ".macro" {
+            return Err(AsmError::new(first.span, "Macros cannot be defined inside a macro"));
+        }
+        if first.kind == TokenKind::Directive && first.text == ".endm" {
+            let definition = self.recording.take().unwrap();
+            self.macros.insert(definition.name.clone(), definition);
+            return Ok(());
+        }
+        self.recording.as_mut().unwrap().body.push(tokens);
+        Ok(())
+    }
+
+    fn add_statement(&mut self, tokens: &[Token]) -> Result<(), AsmError> {
+        let first = &tokens[0];
+        // `.if:` is a local label, not a directive
+        let is_label = tokens.get(1).is_some_and(|colon| colon.is(":"));
+        if first.kind == TokenKind::Directive && !is_label && self.block_directive(tokens)? {
+            return Ok(());
+        }
+        if !self.assembling() {
+            return Ok(());
+        }
+        if let Some(colon) = tokens.get(1)
+            && colon.is(":")
+            && matches!(first.kind, TokenKind::Ident | TokenKind::Directive)
+        {
+            let name = if first.kind == TokenKind::Directive { self.local_name(first)? } else { first.text.clone() };
+            self.symbols.define_label(name.clone(), first.span, self.statements.len())?;
+            // labels made by macro expansions are private and do not open a scope
+            if first.kind == TokenKind::Ident && first.span.expansion.is_none() {
+                self.scope = Some(name);
+            }
+            if tokens.len() > 2 {
+                self.add_statement(&tokens[2..])?;
+            }
+            return Ok(());
+        }
+        let tokens = &self.localize(tokens)?;
+        match (first.kind, tokens.get(1)) {
+            (TokenKind::Ident, Some(equals)) if equals.is("=") => {
+                self.symbols.define_constant(first, &tokens[2..], equals.span)?;
+            }
+            (TokenKind::Directive, _) if first.text == ".equ" || first.text == ".define" => {
+                match tokens.get(1) {
+                    Some(name) => self.symbols.define_constant(name, &tokens[2..], name.span)?,
+                    None => return Err(AsmError::new(first.span, format!("{} expects a name and a value", first.text))),
+                }
+            }
+            (TokenKind::Directive, _) if first.text == ".macro" => {
+                let definition = Macro::define(tokens)?;
+                if is_reserved(&definition.name) {
+                    return Err(AsmError::new(definition.span, format!("Macro name {} is a register or instruction name", definition.name)));
+                }
+                if self.macros.contains_key(&definition.name) {
+                    return Err(AsmError::new(definition.span, format!("Macro {} is already defined", definition.name)));
+                }
+                self.recording = Some(definition);
+            }
+            (TokenKind::Directive, _) if first.text == ".include" => self.include(tokens)?,
+            (TokenKind::Directive, _) if first.text == ".struct" => {
+                let (definition, fields) = StructDef::open(tokens)?;
+                if self.structs.contains_key(&definition.name) {
+                    return Err(AsmError::new(definition.span, format!("Struct {} is already defined", definition.name)));
+                }
+                self.layout = Some(definition);
+                self.struct_fields(fields)?;
+            }
+            (TokenKind::Directive, _) if first.text == ".array" => self.array(tokens)?,
+            (TokenKind::Directive, _) if first.text == ".extern" => {
+                for name in names(tokens)? {
+                    self.symbols.declare_extern(name)?;
+                }
+            }
+            (TokenKind::Directive, _) if first.text == ".export" => {
+                let names = names(tokens)?.into_iter().cloned();
+                self.exports.extend(names);
+            }
+            (TokenKind::Directive, _) if first.text == ".endm" => {
+                return Err(AsmError::new(first.span, ".endm without .macro"));
+            }
+            (TokenKind::Directive, _) => self.directives.push(Statement::new(tokens)),
+            (TokenKind::Ident, _) if self.macros.contains_key(&first.text) => self.expand(tokens)?,
+            (TokenKind::Ident, _) => self.statements.push(Statement::new(tokens)),
+            _ => return Err(AsmError::new(first.span, format!("Expected an instruction, found '{}'", first.text))),
+        }
+        Ok(())
+    }
+
+    // Fields of the open struct, which is complete at its `}`.
+    fn struct_fields(&mut self, tokens: &[Token]) -> Result<(), AsmError> {
+        let definition = self.layout.as_mut().unwrap();
+        let (definitions, closed) = definition.fields(tokens)?;
+        for (name, value, span) in definitions {
+            self.symbols.define_generated(name, value, span)?;
+        }
+        if closed {
+            let definition = self.layout.take().unwrap();
+            self.structs.insert(definition.name, definition.fields);
+        }
+        Ok(())
+    }
+
+    // `.array NAME [TYPE] COUNT at ADDR`; the count must be known here, as
+    // every element gets constants of its own.
+    fn array(&mut self, tokens: &[Token]) -> Result<(), AsmError> {
+        let array = ArrayDef::parse(tokens)?;
+        let fields = match array.element {
+            Some(element) => self.structs.get(&element.text)
+                .ok_or_else(|| AsmError::new(element.span, format!("Unknown struct: {}", element.text)))?
+                .clone(),
+            None => Vec::new(),
+        };
+        let ctx = Context { symbols: &self.symbols, here: None, depth: 0, externs: None, base: 0 };
+        let count = ctx.eval(&array.count, 1, 256, "Array length")? as usize;
+        for (name, value, span) in array.definitions(count, &fields) {
+            self.symbols.define_generated(name, value, span)?;
+        }
+        self.arrays.push(array.name.clone());
+        Ok(())
+    }
+
+    fn expand(&mut self, call: &[Token]) -> Result<(), AsmError> {
+        let name = &call[0].text;
+        if self.depth(call[0].span) >= MAX_DEPTH {
+            return Err(AsmError::new(
+                call[0].span,
+                format!("Macro {} is nested more than {} levels deep", name, MAX_DEPTH),
+            ));
+        }
+        let id = self.expansions.len();
+        let body = self.macros[name].expand(call, id)?;
+        self.expansions.push(Expansion { name: name.clone(), call: call[0].span.to(call[call.len() - 1].span) });
+        let open = self.blocks.len();
+        for tokens in body.iter().filter(|tokens| !tokens.is_empty()) {
+            self.add_statement(tokens)?;
+        }
+        if self.blocks.len() != open {
+            return Err(AsmError::new(call[0].span, format!("Macro {} leaves a block open", name)));
+        }
+        Ok(())
+    }
+
+    fn assembling(&self) -> bool {
+        self.blocks.iter().rev().find_map(|open| match open.block {
+            Block::Assembly { outer, value, in_else } => Some(outer && value != in_else),
+            _ => None,
+        }).unwrap_or(true)
+    }
+
+    // Handles `.if`, `.ifdef`, `.ifndef`, `.else`, `.endif`, `.while`,
+    // `.endwhile`, `.for` and `.endfor`; returns false for any other directive.
+    fn block_directive(&mut self, tokens: &[Token]) -> Result<bool, AsmError> {
+        let first = &tokens[0];
+        let span = first.span;
+        let assembling = self.assembling();
+        let block = match first.text.as_str() {
+            ".if" if assembling && is_runtime(&tokens[1..]) => {
+                let test = runtime_test(&tokens[1..], span)?;
+                let id = self.next_id();
+                self.branch_unless(&test, format!("IF.{}.ELSE", id), span)?;
+                Block::If { id, in_else: false }
+            }
+            ".if" | ".ifdef" | ".ifndef" => {
+                // conditions in skipped code are not evaluated
+                let value = assembling && self.condition(tokens)?;
+                Block::Assembly { outer: assembling, value, in_else: false }
+            }
+            ".while" if assembling => {
+                let test = runtime_test(&tokens[1..], span)?;
+                let id = self.next_id();
+                self.label(format!("WHILE.{}.TOP", id), span)?;
+                self.branch_unless(&test, format!("WHILE.{}.END", id), span)?;
+                Block::While { id }
+            }
+            ".for" if assembling => {
+                let (reg, first_value, last) = for_range(tokens)?;
+                if !is_runtime(first_value) && !is_runtime(last) {
+                    self.for_ranges.push((span, join(first_value), join(last)));
+                }
+                let id = self.next_id();
+                self.emit(span, "MOV", &[std::slice::from_ref(&reg), first_value])?;
+                self.label(format!("FOR.{}.TOP", id), span)?;
+                Block::For { id, reg, last: last.to_vec() }
+            }
+            ".while" => Block::Skipped(".endwhile"),
+            ".for" => Block::Skipped(".endfor"),
+            ".else" | ".endif" | ".endwhile" | ".endfor" if tokens.len() > 1 => {
+                return Err(AsmError::new(tokens[1].span, format!("Unexpected operand after {}", first.text)));
+            }
+            ".else" => {
+                match self.blocks.last_mut().map(|open| &mut open.block) {
+                    Some(Block::Assembly { in_else: true, .. } | Block::If { in_else: true, .. }) => {
+                        return Err(AsmError::new(span, "Second .else for the same .if"));
+                    }
+                    Some(Block::Assembly { in_else, .. }) => *in_else = true,
+                    Some(Block::If { id, in_else }) => {
+                        *in_else = true;
+                        let id = *id;
+                        self.emit(span, "JMP", &[&[synthetic(&format!("IF.{}.END", id), span)]])?;
+                        self.label(format!("IF.{}.ELSE", id), span)?;
+                    }
+                    _ => return Err(AsmError::new(span, ".else without .if")),
+                }
+                return Ok(true);
+            }
+            ".endif" | ".endwhile" | ".endfor" => {
+                self.close(first)?;
+                return Ok(true);
+            }
+            _ => return Ok(false),
+        };
+        self.blocks.push(OpenBlock { block, span });
+        Ok(true)
+    }
+
+    fn close(&mut self, end: &Token) -> Result<(), AsmError> {
+        let span = end.span;
+        match self.blocks.last() {
+            Some(open) if open.block.end() == end.text => {}
+            Some(open) => {
+                return Err(AsmError::new(span, format!("{} found where {} was expected", end.text, open.block.end()))
+                    .with_note(open.span, "block opened here"));
+            }
+            None => return Err(AsmError::new(span, format!("{} without an open block", end.text))),
+        }
+        match self.blocks.pop().unwrap().block {
+            Block::If { id, in_else } => {
+                if !in_else {
+                    self.label(format!("IF.{}.ELSE", id), span)?;
+                }
+                self.label(format!("IF.{}.END", id), span)?;
+            }
+            Block::While { id } => {
+                self.emit(span, "JMP", &[&[synthetic(&format!("WHILE.{}.TOP", id), span)]])?;
+                self.label(format!("WHILE.{}.END", id), span)?;
+            }
+            Block::For { id, reg, last } => {
+                let end_label = format!("FOR.{}.END", id);
+                self.emit(span, "CMP", &[std::slice::from_ref(&reg), &last])?;
+                self.emit(span, "JZ", &[&[synthetic(&end_label, span)]])?;
+                self.emit(span, "ADD", &[&[reg], &[synthetic("1", span)]])?;
+                self.emit(span, "JMP", &[&[synthetic(&format!("FOR.{}.TOP", id), span)]])?;
+                self.label(end_label, span)?;
+            }
+            Block::Assembly { .. } | Block::Skipped(_) => {}
+        }
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.generated += 1;
+        self.generated
+    }
+
+    // Jumps to `target` when the run-time test fails.
+    fn branch_unless(&mut self, test: &RuntimeTest, target: String, span: Span) -> Result<(), AsmError> {
+        self.emit(span, "CMP", &[std::slice::from_ref(&test.reg), &test.src])?;
+        let jump = if test.equal { "JNZ" } else { "JZ" };
+        self.emit(span, jump, &[&[synthetic(&target, span)]])
+    }
+
+    // Adds an instruction generated by the assembler.
+    fn emit(&mut self, span: Span, mnemonic: &str, operands: &[&[Token]]) -> Result<(), AsmError> {
+        let mut tokens = vec![synthetic(mnemonic, span)];
+        for (i, operand) in operands.iter().enumerate() {
+            if i > 0 {
+                tokens.push(Token { kind: TokenKind::Punct, text: ",".to_string(), span, space_before: false });
+            }
+            tokens.extend(operand.iter().cloned());
+        }
+        self.add_statement(&tokens)
+    }
+
+    // Generated labels do not open a scope for `.local` labels.
+    fn label(&mut self, name: String, span: Span) -> Result<(), AsmError> {
+        self.symbols.define_label(name, span, self.statements.len())
+    }
+
+    fn condition(&self, tokens: &[Token]) -> Result<bool, AsmError> {
+        let first = &tokens[0];
+        if first.text == ".if" {
+            if tokens.len() < 2 {
+                return Err(AsmError::new(first.span, ".if expects a condition"));
+            }
+            let ctx = Context { symbols: &self.symbols, here: None, depth: 0, externs: None, base: 0 };
+            return Ok(ctx.eval(&join(&tokens[1..]), i64::MIN, i64::MAX, "Value")? != 0);
+        }
+        match tokens {
+            [_, name] if name.kind == TokenKind::Ident => {
+                let defined = self.symbols.definitions.contains_key(&name.text) || self.macros.contains_key(&name.text);
+                Ok(defined == (first.text == ".ifdef"))
+            }
+            _ => Err(AsmError::new(first.span, format!("{} expects a name", first.text))),
+        }
+    }
+
+    // `.name` refers to the label `GLOBAL.name`, where GLOBAL is the last
+    // global label defined before it.
+    fn local_name(&self, token: &Token) -> Result<String, AsmError> {
+        match &self.scope {
+            Some(scope) => Ok(format!("{}{}", scope, token.text)),
+            None => Err(AsmError::new(token.span, format!("Local label {} has no global label before it", token.text))),
+        }
+    }
+
+    // Replaces local label references in operands with their full names.
+    fn localize(&self, tokens: &[Token]) -> Result<Vec<Token>, AsmError> {
+        let mut localized = tokens.to_vec();
+        for token in localized.iter_mut().skip(1).filter(|token| token.kind == TokenKind::Directive) {
+            token.text = self.local_name(token)?;
+            token.kind = TokenKind::Ident;
+        }
+        Ok(localized)
+    }
+
+    // How many macro expansions produced `span`.
+    fn depth(&self, span: Span) -> usize {
+        let mut depth = 0;
+        let mut expansion = span.expansion;
+        while let Some(id) = expansion {
+            depth += 1;
+            expansion = self.expansions[id].call.expansion;
+        }
+        depth
+    }
+
+    // Adds the chain of macro calls and includes behind the error's location
+    // as notes.
+    fn explain(&self, mut err: AsmError) -> AsmError {
+        let mut trace: Vec<(Span, String)> = Vec::new();
+        let mut expansion = err.span.expansion;
+        while let Some(id) = expansion {
+            let call = &self.expansions[id];
+            let note = (call.call, format!("in expansion of macro {}", call.name));
+            // a recursive macro would repeat the same call many times
+            let repeated = trace.last().is_some_and(|(span, message)| {
+                (span.line, span.col, message) == (note.0.line, note.0.col, &note.1)
+            });
+            if !repeated {
+                trace.push(note);
+            }
+            expansion = call.call.expansion;
+        }
+        let last = trace.last().map_or(err.span, |(span, _)| *span);
+        let mut included_from = self.files.get(last.file).and_then(|file| file.included_from);
+        while let Some(span) = included_from {
+            trace.push((span, "included from here".to_string()));
+            included_from = self.files.get(span.file).and_then(|file| file.included_from);
+        }
+        err.notes.splice(0..0, trace);
+        err
+    }
+
+    // Where `statement` was written; code from a macro also gets the
+    // outermost call that produced it.
+    fn locate(&self, statement: &Statement) -> SourceLocation {
+        let span = statement.span;
+        let file = &self.files[span.file];
+        let text = file.text.lines().nth(span.line.wrapping_sub(1)).unwrap_or_default();
+        let expanded_at = span.expansion.map(|id| {
+            let mut outer = &self.expansions[id];
+            while let Some(parent) = outer.call.expansion {
+                outer = &self.expansions[parent];
+            }
+            let call = format!("{}:{}", self.files[outer.call.file].path.display(), outer.call.line);
+            (self.expansions[id].name.clone(), call)
+        });
+        SourceLocation { file: file.path.display().to_string(), line: span.line, text: text.trim().to_string(), expanded_at }
+    }
+
+    // Labels and the constants that have a value, sorted by value.
+    fn symbol_table(&self) -> Vec<Symbol> {
+        let ctx = Context { symbols: &self.symbols, here: None, depth: 0, externs: None, base: 0 };
+        let labels = self.symbols.labels.iter()
+            .map(|(name, &index)| Symbol { name: name.clone(), value: index as i64, kind: SymbolKind::Label });
+        let constants = self.symbols.constants.keys().filter_map(|name| {
+            let value = ctx.symbol(name)?.ok()?;
+            Some(Symbol { name: name.clone(), value, kind: SymbolKind::Constant })
+        });
+        let mut symbols: Vec<Symbol> = labels.chain(constants).collect();
+        symbols.sort_by(|a, b| (a.value, &a.name).cmp(&(b.value, &b.name)));
+        symbols
+    }
+
+    // The memory image, and the addresses each data directive wrote.
+    fn data(&self) -> Result<(Vec<DataBlock>, Vec<DataLocation>), AsmError> {
+        let mut image = DataImage::default();
+        let mut locations = Vec::new();
+        let ctx = Context { symbols: &self.symbols, here: None, depth: 0, externs: None, base: 0 };
+        for directive in &self.directives {
+            let start = image.cursor;
+            image.directive(directive, &ctx)?;
+            // `.org` moves the cursor without writing
+            if directive.name.text != ".org" && image.cursor > start {
+                locations.push((start..image.cursor, self.locate(directive)));
+            }
+        }
+        for name in &self.arrays {
+            let end = evaluate(&format!("{0} + {0}.size", name.text), &ctx).map_err(|e| AsmError::new(name.span, e))?;
+            if !(0..=256).contains(&end) {
+                return Err(AsmError::new(name.span, format!("Array {} does not fit in memory (ends at {})", name.text, end - 1)));
+            }
+        }
+        Ok((image.blocks, locations))
+    }
+}
+
+// Labels and named constants. Both live in one global namespace: a name is
+// defined once for the whole program and can be used before its definition.
+#[derive(Default)]
+struct Symbols {
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, Operand>,
+    // where each label or constant was defined
+    definitions: HashMap<String, Span>,
+    // `.extern` names, defined by another module
+    externs: HashSet<String>,
+}
+
+impl Symbols {
+    fn define_label(&mut self, name: String, span: Span, index: usize) -> Result<(), AsmError> {
+        self.claim(&name, span, "Label")?;
+        self.labels.insert(name, index);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &Token, value: &[Token], at: Span) -> Result<(), AsmError> {
+        if name.kind != TokenKind::Ident {
+            return Err(AsmError::new(name.span, format!("Invalid constant name: {}", name.text)));
+        }
+        if value.is_empty() {
+            return Err(AsmError::new(at, format!("Missing value for constant {}", name.text)));
+        }
+        self.claim(&name.text, name.span, "Constant")?;
+        self.constants.insert(name.text.clone(), join(value));
+        Ok(())
+    }
+
+    // A constant made by a `.struct` or `.array`.
+    fn define_generated(&mut self, name: String, value: String, span: Span) -> Result<(), AsmError> {
+        self.claim(&name, span, "Constant")?;
+        self.constants.insert(name, Operand { text: value, span });
+        Ok(())
+    }
+
+    fn claim(&mut self, name: &str, span: Span, what: &str) -> Result<(), AsmError> {
+        if is_reserved(name) {
+            return Err(AsmError::new(span, format!("{} name {} is a register or instruction name", what, name)));
+        }
+        if let Some(&first) = self.definitions.get(name) {
+            return Err(AsmError::new(span, format!("{} is already defined", name)).with_note(first, "first defined here"));
+        }
+        self.definitions.insert(name.to_string(), span);
+        Ok(())
+    }
+
+    fn declare_extern(&mut self, name: &Token) -> Result<(), AsmError> {
+        if name.kind != TokenKind::Ident {
+            return Err(AsmError::new(name.span, format!("Invalid symbol name: {}", name.text)));
+        }
+        self.claim(&name.text, name.span, "External symbol")?;
+        self.externs.insert(name.text.clone());
+        Ok(())
+    }
+
+    // The external symbols an operand uses, directly or through constants.
+    fn externs_in(&self, text: &str, depth: usize) -> Vec<String> {
+        let Ok(tokens) = tokenize_line(text, 0, 0) else { return Vec::new() };
+        let mut names = Vec::new();
+        for token in tokens.iter().filter(|token| token.kind == TokenKind::Ident) {
+            if self.externs.contains(&token.text) {
+                names.push(token.text.clone());
+            } else if let Some(value) = self.constants.get(&token.text)
+                && depth < 32
+            {
+                names.extend(self.externs_in(&value.text, depth + 1));
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    // Context for evaluating the operands of the instruction at `index`.
+    fn at(&self, index: usize) -> Context<'_> {
+        Context { symbols: self, here: Some(index), depth: 0, externs: None, base: 0 }
+    }
+}
+
+// Evaluates expressions in operands, checking that results fit their target.
+struct Context<'a> {
+    symbols: &'a Symbols,
+    here: Option<usize>,
+    depth: usize,
+    // the value external symbols stand for while an object is assembled
+    externs: Option<i64>,
+    // added to labels and `$`, to find operands that move with the module
+    base: i64,
+}
+
+impl Scope for Context<'_> {
+    fn symbol(&self, name: &str) -> Option<Result<i64, String>> {
+        if let Some(&index) = self.symbols.labels.get(name) {
+            return Some(Ok(index as i64 + self.base));
+        }
+        if self.symbols.externs.contains(name) {
+            return Some(self.externs.ok_or_else(|| {
+                format!("{} is defined in another module and can only be used in instructions of an object file (asm -c)", name)
+            }));
+        }
+        let value = self.symbols.constants.get(name)?;
+        if self.depth > 32 {
+            return Some(Err(format!("Constant {} is defined in terms of itself", name)));
+        }
+        // `$` means nothing inside a constant, whatever instruction uses it
+        let inner = Context { symbols: self.symbols, here: None, depth: self.depth + 1, externs: self.externs, base: self.base };
+        let result = evaluate(&value.text, &inner);
+        if self.depth == 0 {
+            Some(result.map_err(|e| format!("{} (in constant {})", e, name)))
         } else {
-            panic!("Invalid memory source: {}", src);
+            Some(result)
+        }
+    }
+
+    fn here(&self) -> Option<i64> {
+        self.here.map(|index| index as i64 + self.base)
+    }
+}
+
+impl Context<'_> {
+    fn eval(&self, operand: &Operand, min: i64, max: i64, what: &str) -> Result<i64, AsmError> {
+        let value = evaluate_at(&operand.text, self).map_err(|(message, operator)| {
+            AsmError::new(operator.map_or(operand.span, |operator| operand.span_of(operator)), message)
+        })?;
+        if value < min || value > max {
+            return Err(AsmError::new(
+                operand.span,
+                format!("{} {} is out of range ({} to {}): {}", what, value, min, max, operand.text),
+            ));
         }
+        Ok(value)
+    }
+
+    // An 8-bit value; -128..-1 are stored in two's complement.
+    fn byte(&self, operand: &Operand) -> Result<u8, AsmError> {
+        Ok(self.eval(operand, -128, 255, "Value")? as u8)
+    }
+
+    fn address(&self, operand: &Operand) -> Result<u8, AsmError> {
+        Ok(self.eval(operand, 0, 255, "Address")? as u8)
+    }
+
+    fn word(&self, operand: &Operand) -> Result<u16, AsmError> {
+        Ok(self.eval(operand, -32768, 65535, "Value")? as u16)
+    }
+
+    fn target(&self, operand: &Operand) -> Result<usize, AsmError> {
+        Ok(self.eval(operand, 0, 65535, "Jump target")? as usize)
+    }
+
+    fn count(&self, operand: &Operand) -> Result<usize, AsmError> {
+        Ok(self.eval(operand, 0, i64::MAX, "Value")? as usize)
+    }
+}
+
+// `REG == SRC`, `REG != SRC` or a bare `REG` (true when not zero).
+struct RuntimeTest {
+    reg: Token,
+    equal: bool,
+    src: Vec<Token>,
+}
+
+// A condition is tested by the program when it mentions a register or memory.
+fn is_runtime(tokens: &[Token]) -> bool {
+    tokens.iter().any(|token| token.is("[") || (token.kind == TokenKind::Ident && reg_name(&token.text).is_some()))
+}
+
+fn runtime_test(tokens: &[Token], at: Span) -> Result<RuntimeTest, AsmError> {
+    if tokens.is_empty() {
+        return Err(AsmError::new(at, "Missing condition"));
+    }
+    let mut depth = 0usize;
+    let mut split = None;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text.as_str() {
+            "[" | "(" => depth += 1,
+            "]" | ")" => depth = depth.saturating_sub(1),
+            "==" | "!=" if depth == 0 && token.kind == TokenKind::Punct => split = Some(i),
+            "<" | ">" | "<=" | ">=" | "&&" | "||" if depth == 0 && token.kind == TokenKind::Punct => {
+                return Err(AsmError::new(token.span, "Only == and != can be tested at run time"));
+            }
+            _ => {}
+        }
+    }
+    let (lhs, equal, rhs) = match split {
+        Some(i) => (&tokens[..i], tokens[i].is("=="), tokens[i + 1..].to_vec()),
+        None => (tokens, false, vec![Token { kind: TokenKind::Number, ..synthetic("0", at) }]),
+    };
+    if lhs.is_empty() || rhs.is_empty() {
+        return Err(AsmError::new(at, "Missing operand in condition"));
+    }
+    let is_register = |side: &[Token]| matches!(side, [token] if reg_name(&token.text).is_some());
+    if is_register(lhs) {
+        Ok(RuntimeTest { reg: lhs[0].clone(), equal, src: rhs })
+    } else if is_register(&rhs) {
+        Ok(RuntimeTest { reg: rhs[0].clone(), equal, src: lhs.to_vec() })
     } else {
-        Source::Reg(parse_reg(src))
+        Err(AsmError::new(lhs[0].span, "A run-time condition needs a register on one side, e.g. A == [5]"))
     }
 }
 
-fn parse_mem_src(src: &str) -> MemSrc {
-    if src.starts_with("[") && src.ends_with(']') {
-        if src[1..src.len() - 1].chars().all(|c| c.is_alphabetic()) {
-            let reg = parse_reg(&src[1..src.len() - 1]);
-            return MemSrc::Reg(reg);
+// `.for REG = FIRST to LAST`
+fn for_range(tokens: &[Token]) -> Result<(Token, &[Token], &[Token]), AsmError> {
+    let usage = || AsmError::new(tokens[0].span, ".for expects REG = FIRST to LAST");
+    match tokens {
+        [_, reg, equals, rest @ ..] if equals.is("=") => {
+            if reg_name(&reg.text).is_none() {
+                return Err(AsmError::new(reg.span, format!("Unknown register: {}", reg.text)));
+            }
+            let to = rest.iter().position(|token| token.kind == TokenKind::Ident && token.text == "to").ok_or_else(usage)?;
+            if to == 0 || to + 1 == rest.len() {
+                return Err(usage());
+            }
+            Ok((reg.clone(), &rest[..to], &rest[to + 1..]))
         }
-        let addr = src[1..src.len() - 1].parse::<u8>().expect("Expected a numeric memory address inside brackets");
-        MemSrc::Addr(addr)
+        _ => Err(usage()),
+    }
+}
+
+// The names listed after `.extern` or `.export`.
+fn names(tokens: &[Token]) -> Result<Vec<&Token>, AsmError> {
+    let ranges = operand_ranges(&tokens[1..]);
+    if ranges.is_empty() {
+        return Err(AsmError::new(tokens[0].span, format!("{} expects symbol names", tokens[0].text)));
+    }
+    ranges.into_iter().map(|(start, end)| {
+        let name = &tokens[1 + start];
+        if end - start != 1 || name.kind != TokenKind::Ident {
+            return Err(AsmError::new(name.span, format!("Invalid symbol name: {}", join(&tokens[1 + start..1 + end]).text)));
+        }
+        Ok(name)
+    }).collect()
+}
+
+fn synthetic(text: &str, span: Span) -> Token {
+    Token { kind: TokenKind::Ident, text: text.to_string(), span, space_before: true }
+}
+
+fn is_reserved(name: &str) -> bool {
+    matches!(name, "A" | "B" | "C" | "D" | "E") || MNEMONICS.contains(&name)
+}
+
+// Memory image built by .org, .byte, .word, .string and .fill.
+#[derive(Default)]
+struct DataImage {
+    blocks: Vec<DataBlock>,
+    cursor: usize,
+    written: Vec<bool>,
+}
+
+impl DataImage {
+    fn directive(&mut self, directive: &Statement, ctx: &Context) -> Result<(), AsmError> {
+        match (directive.name.text.as_str(), directive.operands.as_slice()) {
+            (".org", [addr]) => {
+                self.cursor = ctx.address(addr)? as usize;
+            }
+            (".byte", values) if !values.is_empty() => {
+                for value in values {
+                    self.push(ctx.byte(value)?, value.span)?;
+                }
+            }
+            (".word", values) if !values.is_empty() => {
+                for value in values {
+                    for byte in ctx.word(value)?.to_le_bytes() {
+                        self.push(byte, value.span)?;
+                    }
+                }
+            }
+            (".string", [text]) => {
+                let bytes = parse_string(&text.text).map_err(|e| AsmError::new(text.span, e))?;
+                for byte in bytes {
+                    self.push(byte, text.span)?;
+                }
+                self.push(0, text.span)?;
+            }
+            (".fill", [count, rest @ ..]) if rest.len() <= 1 => {
+                let value = match rest.first() {
+                    Some(value) => ctx.byte(value)?,
+                    None => 0,
+                };
+                for _ in 0..ctx.count(count)? {
+                    self.push(value, count.span)?;
+                }
+            }
+            (".org" | ".byte" | ".word" | ".string" | ".fill", _) => {
+                return Err(AsmError::new(directive.span, format!("Wrong operands for {}", directive.name.text)));
+            }
+            (name, _) => return Err(AsmError::new(directive.name.span, format!("Unknown directive: {}", name))),
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, byte: u8, span: Span) -> Result<(), AsmError> {
+        if self.cursor > 255 {
+            return Err(AsmError::new(span, "Data does not fit in memory (past address 255)"));
+        }
+        if self.written.is_empty() {
+            self.written = vec![false; 256];
+        }
+        if self.written[self.cursor] {
+            return Err(AsmError::new(span, format!("Data at address {} overlaps earlier data", self.cursor)));
+        }
+        self.written[self.cursor] = true;
+        match self.blocks.last_mut() {
+            Some(block) if block.addr as usize + block.bytes.len() == self.cursor => block.bytes.push(byte),
+            _ => self.blocks.push(DataBlock { addr: self.cursor as u8, bytes: vec![byte] }),
+        }
+        self.cursor += 1;
+        Ok(())
+    }
+}
+
+fn parse_reg(reg: &Operand) -> Result<Reg, AsmError> {
+    reg_name(&reg.text).ok_or_else(|| AsmError::new(reg.span, format!("Unknown register: {}", reg.text)))
+}
+
+fn reg_name(reg: &str) -> Option<Reg> {
+    match reg {
+        "A" => Some(Reg::A),
+        "B" => Some(Reg::B),
+        "C" => Some(Reg::C),
+        "D" => Some(Reg::D),
+        "E" => Some(Reg::E),
+        _ => None,
+    }
+}
+
+fn parse_source(src: &Operand, ctx: &Context) -> Result<Source, AsmError> {
+    if src.text.starts_with('[') {
+        Ok(Source::Mem(parse_mem_src(src, ctx)?))
+    } else if let Some(reg) = reg_name(&src.text) {
+        Ok(Source::Reg(reg))
     } else {
-        panic!("Invalid memory source: {}", src);
+        Ok(Source::Lit(ctx.byte(src)?))
+    }
+}
+
+fn parse_mem_src(src: &Operand, ctx: &Context) -> Result<MemSrc, AsmError> {
+    match src.text.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        Some(inner) => match reg_name(inner.trim()) {
+            Some(reg) => Ok(MemSrc::Reg(reg)),
+            None => Ok(MemSrc::Addr(ctx.address(&Operand { text: inner.to_string(), span: src.span })?)),
+        },
+        None => Err(AsmError::new(src.span, format!("Invalid memory source: {}", src.text))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assembled_with(text: &str, defines: &[&str]) -> Result<Program, String> {
+        let defines: Vec<String> = defines.iter().map(|define| define.to_string()).collect();
+        let mut assembler = Assembler::default();
+        assembler.define(&defines).map_err(|err| err.to_string())?;
+        assembler.assemble(PathBuf::from("test.e8"), text.to_string()).map_err(|err| err.to_string())
+    }
+
+    fn assembled(text: &str) -> Result<Program, String> {
+        assembled_with(text, &[])
+    }
+
+    fn code(text: &str) -> Vec<String> {
+        let program = assembled(text).unwrap_or_else(|e| panic!("{}", e));
+        program.instructions.iter().map(|instruction| instruction.to_string()).collect()
+    }
+
+    fn symbol(program: &Program, name: &str) -> Option<i64> {
+        program.source.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.value)
+    }
+
+    // the files are named after the test, so tests can run at the same time
+    fn temp_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("e8bit-{}-{}", name, std::process::id()));
+        for (path, text) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn data_directives() {
+        let program = assembled(".org 2\n.byte 36, 0\n.string \"Hi\"\n.word 1000\n.fill 2, 255").unwrap();
+        let data: Vec<(u8, Vec<u8>)> = program.data.iter().map(|block| (block.addr, block.bytes.clone())).collect();
+        assert_eq!(data, [(2, vec![36, 0, b'H', b'i', 0, 0xe8, 3, 255, 255])]);
+        assert_eq!(program.source.data_location(6).unwrap().text, ".string \"Hi\"");
+    }
+
+    #[test]
+    fn overlapping_data() {
+        assert_eq!(assembled(".org 2\n.byte 1, 2\n.org 3\n.byte 3").unwrap_err(), "4:7: Data at address 3 overlaps earlier data");
+        assert_eq!(assembled(".org 255\n.byte 1, 2").unwrap_err(), "2:10: Data does not fit in memory (past address 255)");
+    }
+
+    #[test]
+    fn constants() {
+        let text = "SCORE = 10\n.equ STEP SCORE + 2\n.define LAST END - 1\nMOV A [SCORE]\nADD A STEP\nJMP LAST\nEND:";
+        assert_eq!(code(text), ["MOV A [10]", "ADD A 12", "JMP 2"]);
+        assert_eq!(assembled("X = 1\n.equ X 2").unwrap_err(), "2:6: X is already defined");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sleep_and_frame_rate_ranges() {
+        assert_eq!(code("SLP 65535\nFPS 0"), ["SLP 65535", "FPS 0"]);
+        assert_eq!(assembled("SLP 65536").unwrap_err(), "1:5: Sleep duration 65536 is out of range (0 to 65535): 65536");
+        assert_eq!(assembled("FPS 256").unwrap_err(), "1:5: Frame rate 256 is out of range (0 to 255): 256");
+    }
+
+    #[test]
+    fn local_labels() {
+        assert_eq!(code("MAIN:\n.loop: JMP .loop\nNEXT:\n.loop: JMP .loop\nJMP MAIN.loop"), ["JMP 0", "JMP 1", "JMP 0"]);
+        assert_eq!(code("MAIN:\n.org: JMP .org"), ["JMP 0"]);
+        assert_eq!(assembled("DONE:\nPRINT A\nDONE:").unwrap_err(), "3:1: DONE is already defined");
+        assert_eq!(assembled(".loop:").unwrap_err(), "1:1: Local label .loop has no global label before it");
+    }
+
+    #[test]
+    fn macro_labels_are_per_call() {
+        let text = ".macro BOUNCE REG, LIMIT\nCMP REG LIMIT\nJNZ DONE\nMOV REG 0\nDONE:\n.endm\nBOUNCE B, 10\nBOUNCE C 5-1";
+        assert_eq!(code(text), ["CMP B 10", "JNZ 3", "MOV B 0", "CMP C 4", "JNZ 6", "MOV C 0"]);
+        let program = assembled(text).unwrap();
+        assert_eq!(symbol(&program, "BOUNCE.0.DONE"), Some(3));
+        assert_eq!(symbol(&program, "BOUNCE.1.DONE"), Some(6));
+        let location = program.source.location(3).unwrap();
+        assert_eq!((location.line, location.text.as_str()), (2, "CMP REG LIMIT"));
+        assert_eq!(location.expanded_at, Some(("BOUNCE".to_string(), "test.e8:8".to_string())));
+    }
+
+    #[test]
+    fn macro_depth() {
+        // a macro calling itself until its parameter reaches zero
+        let countdown = |n| format!(".macro DOWN N\n.if N > 0\nPRINT A\nDOWN N - 1\n.endif\n.endm\nDOWN {}", n);
+        assert_eq!(code(&countdown(15)).len(), 15);
+        let err = assembled(&countdown(16)).unwrap_err();
+        assert!(err.ends_with("Macro DOWN is nested more than 16 levels deep"), "{}", err);
+    }
+
+    #[test]
+    fn includes() {
+        let dir = temp_dir("includes", &[
+            ("main.e8", ".include \"lib/a.e8\"\n.include \"b.e8\"\nJMP SHARED"),
+            ("lib/a.e8", "PRINT A\n.include \"c.e8\""),
+            ("lib/c.e8", "SHARED:\nPRINT C"),
+            ("dirs/b.e8", "PRINT B"),
+            ("cycle/x.e8", ".include \"y.e8\""),
+            ("cycle/y.e8", ".include \"x.e8\""),
+        ]);
+        let include = |file: &str, include_dirs: &[PathBuf]| {
+            let path = dir.join(file);
+            let text = std::fs::read_to_string(&path).unwrap();
+            let mut assembler = Assembler { include_dirs: include_dirs.to_vec(), ..Assembler::default() };
+            assembler.assemble(path, text)
+                .map(|program| program.instructions.iter().map(|instruction| instruction.to_string()).collect::<Vec<_>>())
+                .map_err(|err| err.message)
+        };
+        // next to the including file first, then in the include directories
+        assert_eq!(include("main.e8", &[dir.join("dirs")]).unwrap(), ["PRINT A", "PRINT C", "PRINT B", "JMP 1"]);
+        assert_eq!(include("main.e8", &[]).unwrap_err(), "Cannot find include file b.e8");
+        let cycle = include("cycle/x.e8", &[]).unwrap_err();
+        let (x, y) = (dir.join("cycle/x.e8").display().to_string(), dir.join("cycle/y.e8").display().to_string());
+        assert_eq!(cycle, format!("Include cycle: {} -> {} -> {}", x, y, x));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn conditionals_and_defines() {
+        let text = ".ifndef LIVES\nLIVES = 3\n.endif\n.ifdef DEBUG\nPRINT C\n.else\nCLS\n.endif\n.if LIVES > 5\nHALT\n.endif";
+        let run = |defines: &[&str]| -> Vec<String> {
+            let program = assembled_with(text, defines).unwrap();
+            program.instructions.iter().map(|instruction| instruction.to_string()).collect()
+        };
+        assert_eq!(run(&[]), ["CLS"]);
+        assert_eq!(run(&["DEBUG", "LIVES=6"]), ["PRINT C", "HALT"]);
+        assert_eq!(assembled_with("LIVES = 3", &["LIVES=6"]).unwrap_err(), "1:1: LIVES is already defined");
+    }
+
+    #[test]
+    fn directive_names_as_local_labels() {
+        assert_eq!(code("MAIN:\n.if:\nJMP .if\n.else: JMP .else"), ["JMP 0", "JMP 1"]);
+    }
+
+    #[test]
+    fn structured_loops() {
+        assert_eq!(code("MOV A 0\n.while A != 10\nADD A 1\n.endwhile"), ["MOV A 0", "CMP A 10", "JZ 5", "ADD A 1", "JMP 1"]);
+        assert_eq!(code(".for B = 0 to 3\nDRAW B 0 '-'\n.endfor").len(), 6);
+        let err = assembled(".for B = 3 to 0\nPRINT B\n.endfor").unwrap_err();
+        assert_eq!(err, "1:1: FIRST (3) is greater than LAST (0), but .for only counts up");
+    }
+
+    #[test]
+    fn struct_and_array_layout() {
+        let text = ".struct Star { x, y[2]\n dir }\n.array STARS Star 3 at 2\n.array BYTES 4 at STARS + STARS.size\n\
+            MOV A Star.dir\nMOV A Star.size\nMOV A [STARS.1.y]\nMOV B STARS.2 + Star.dir\nMOV C BYTES.3\nMOV D BYTES.size";
+        assert_eq!(code(text), ["MOV A 3", "MOV A 4", "MOV A [7]", "MOV B 13", "MOV C 17", "MOV D 4"]);
+        let err = assembled(".array BIG 200 at 100\nHALT").unwrap_err();
+        assert!(err.contains("BIG"), "{}", err);
+    }
+}
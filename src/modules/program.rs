@@ -0,0 +1,102 @@
+use std::fmt;
+use std::ops::Range;
+use crate::modules::vm::Instruction;
+
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub data: Vec<DataBlock>,
+    // index of the first instruction to run
+    pub entry: usize,
+    pub source: SourceInfo,
+}
+
+// Initial memory contents declared with data directives.
+#[derive(Debug, Clone)]
+pub struct DataBlock {
+    pub addr: u8,
+    pub bytes: Vec<u8>,
+}
+
+// What the assembler knew about the program, for runtime errors and debug
+// output.
+#[derive(Debug, Clone, Default)]
+pub struct SourceInfo {
+    // where each instruction was written, by instruction index
+    pub locations: Vec<SourceLocation>,
+    // labels and constants, sorted by value
+    pub symbols: Vec<Symbol>,
+    // the addresses each data directive wrote
+    pub data: Vec<DataLocation>,
+}
+
+// Memory written by a data directive, and where the directive is.
+pub type DataLocation = (Range<usize>, SourceLocation);
+
+#[derive(Debug, Clone)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    // the source line, trimmed
+    pub text: String,
+    // for code pasted in by a macro: the macro and where it was called
+    pub expanded_at: Option<(String, String)>,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.text)?;
+        if let Some((name, call)) = &self.expanded_at {
+            write!(f, " (in macro {} called at {})", name, call)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: i64,
+    pub kind: SymbolKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    Constant,
+}
+
+impl SourceInfo {
+    pub fn location(&self, index: usize) -> Option<&SourceLocation> {
+        self.locations.get(index)
+    }
+
+    // The instruction at `index` relative to the last label at or before
+    // it, e.g. `GAME_LOOP+3`.
+    pub fn label_offset(&self, index: usize) -> Option<String> {
+        let label = self.symbols.iter()
+            .filter(|symbol| symbol.kind == SymbolKind::Label && symbol.value <= index as i64)
+            .max_by_key(|symbol| symbol.value)?;
+        match index as i64 - label.value {
+            0 => Some(label.name.clone()),
+            offset => Some(format!("{}+{}", label.name, offset)),
+        }
+    }
+
+    // The data directive that wrote memory address `addr`.
+    pub fn data_location(&self, addr: usize) -> Option<&SourceLocation> {
+        self.data.iter().find(|(range, _)| range.contains(&addr)).map(|(_, location)| location)
+    }
+
+    // `index` with its label and source line, for messages.
+    pub fn describe(&self, index: usize) -> String {
+        let mut text = format!("instruction {}", index);
+        if let Some(label) = self.label_offset(index) {
+            text += &format!(" ({})", label);
+        }
+        if let Some(location) = self.location(index) {
+            text += &format!(", {}", location);
+        }
+        text
+    }
+}
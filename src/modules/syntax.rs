@@ -0,0 +1,184 @@
+use std::fmt;
+use crate::modules::error::AsmError;
+use crate::modules::lexer::{ operand_ranges, tokenize_line, Token, TokenKind };
+
+// A lossless syntax tree of a source file, for tools that rewrite source:
+// every token keeps the whitespace before it, and every line its trailing
+// whitespace and line ending, so printing the tree gives back the file
+// byte for byte.
+pub struct SyntaxTree {
+    pub lines: Vec<Line>,
+}
+
+pub struct Line {
+    // separated by `;`
+    pub statements: Vec<Statement>,
+    pub comment: Option<Element>,
+    pub trailing: String,
+    // "\n", "\r\n", or "" for a last line without one
+    pub ending: String,
+}
+
+pub struct Statement {
+    pub labels: Vec<Label>,
+    pub body: Option<Body>,
+    // the `;` after the statement
+    pub separator: Option<Element>,
+}
+
+pub struct Label {
+    pub name: Element,
+    pub colon: Element,
+}
+
+// An instruction, directive, macro call or `NAME = value` definition.
+pub struct Body {
+    pub head: Element,
+    // the `=` of a constant definition
+    pub equals: Option<Element>,
+    pub operands: Vec<OperandNode>,
+    // commas after the last operand
+    pub trailing: Vec<Element>,
+}
+
+pub struct OperandNode {
+    // commas before the operand
+    pub commas: Vec<Element>,
+    pub tokens: Vec<Element>,
+}
+
+// A token and the whitespace before it.
+pub struct Element {
+    pub space: String,
+    pub token: Token,
+}
+
+pub fn parse(text: &str) -> Result<SyntaxTree, AsmError> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    let mut line_no = 0;
+    while !rest.is_empty() {
+        line_no += 1;
+        let (line, ending, next) = match rest.find('\n') {
+            Some(end) if rest[..end].ends_with('\r') => (&rest[..end - 1], "\r\n", &rest[end + 1..]),
+            Some(end) => (&rest[..end], "\n", &rest[end + 1..]),
+            None => (rest, "", ""),
+        };
+        lines.push(parse_line(line, ending, line_no)?);
+        rest = next;
+    }
+    Ok(SyntaxTree { lines })
+}
+
+fn parse_line(line: &str, ending: &str, line_no: usize) -> Result<Line, AsmError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut end = 0;
+    let mut elements = Vec::new();
+    for token in tokenize_line(line, line_no, 0)? {
+        let start = token.span.col - 1;
+        let space = chars[end..start].iter().collect();
+        end = start + token.span.len;
+        elements.push(Element { space, token });
+    }
+    let trailing = chars[end..].iter().collect();
+    let comment = match elements.last() {
+        Some(last) if last.token.kind == TokenKind::Comment => elements.pop(),
+        _ => None,
+    };
+    let mut statements = Vec::new();
+    let mut current = Vec::new();
+    for element in elements {
+        if element.token.kind == TokenKind::Separator {
+            statements.push(statement(std::mem::take(&mut current), Some(element)));
+        } else {
+            current.push(element);
+        }
+    }
+    if !current.is_empty() {
+        statements.push(statement(current, None));
+    }
+    Ok(Line { statements, comment, trailing, ending: ending.to_string() })
+}
+
+fn statement(elements: Vec<Element>, separator: Option<Element>) -> Statement {
+    let mut elements = elements.into_iter().peekable();
+    let mut labels = Vec::new();
+    let mut rest: Vec<Element> = Vec::new();
+    while let Some(element) = elements.next() {
+        let is_name = matches!(element.token.kind, TokenKind::Ident | TokenKind::Directive);
+        if rest.is_empty() && is_name && elements.peek().is_some_and(|next| next.token.is(":")) {
+            labels.push(Label { name: element, colon: elements.next().unwrap() });
+        } else {
+            rest.push(element);
+        }
+    }
+    let body = (!rest.is_empty()).then(|| body(rest));
+    Statement { labels, body, separator }
+}
+
+fn body(elements: Vec<Element>) -> Body {
+    let mut elements = elements.into_iter();
+    let head = elements.next().unwrap();
+    let mut rest: Vec<Element> = elements.collect();
+    let equals = if head.token.kind == TokenKind::Ident && rest.first().is_some_and(|e| e.token.is("=")) {
+        Some(rest.remove(0))
+    } else {
+        None
+    };
+    let tokens: Vec<Token> = rest.iter().map(|element| element.token.clone()).collect();
+    let ranges = operand_ranges(&tokens);
+    let mut operands = Vec::new();
+    let mut commas = Vec::new();
+    let mut rest = rest.into_iter().enumerate().peekable();
+    for (start, end) in ranges {
+        while let Some((index, _)) = rest.peek()
+            && *index < start
+        {
+            commas.push(rest.next().unwrap().1);
+        }
+        let tokens = rest.by_ref().take(end - start).map(|(_, element)| element).collect();
+        operands.push(OperandNode { commas: std::mem::take(&mut commas), tokens });
+    }
+    let trailing = rest.map(|(_, element)| element).collect();
+    Body { head, equals, operands, trailing }
+}
+
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            for statement in &line.statements {
+                for label in &statement.labels {
+                    write!(f, "{}{}", label.name, label.colon)?;
+                }
+                if let Some(body) = &statement.body {
+                    write!(f, "{}", body.head)?;
+                    if let Some(equals) = &body.equals {
+                        write!(f, "{}", equals)?;
+                    }
+                    for operand in &body.operands {
+                        for element in operand.commas.iter().chain(&operand.tokens) {
+                            write!(f, "{}", element)?;
+                        }
+                    }
+                    for element in &body.trailing {
+                        write!(f, "{}", element)?;
+                    }
+                }
+                if let Some(separator) = &statement.separator {
+                    write!(f, "{}", separator)?;
+                }
+            }
+            if let Some(comment) = &line.comment {
+                write!(f, "{}", comment)?;
+            }
+            write!(f, "{}{}", line.trailing, line.ending)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.space, self.token.text)
+    }
+}
@@ -1,4 +1,7 @@
 use crate::modules::vm::Vm;
+use crate::modules::program::SymbolKind;
+use crate::modules::disasm::code;
+use crate::modules::keyboard::{ Keyboard, OverflowPolicy };
 use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -20,14 +23,181 @@ pub fn debug(elapsed: Duration, vm: &Vm) {
     println!("Registers: {:?}", vm.reg);
     println!("Memory: {:?}", vm.mem);
     println!("Program Counter: {:?}", vm.pc);
+    if let Some(index) = vm.current_instruction() {
+        println!("Stopped at: {}", vm.source.describe(index));
+    }
     println!("Zero Flag: {:?}", vm.zf);
-    println!("Program: {:?}", vm.program);
-    println!("Program Length: {:?}", vm.program.len());
+    if vm.von_neumann {
+        println!("Program: in memory (von Neumann mode)");
+    } else {
+        println!("Program:");
+        print!("{}", code(&vm.program, &vm.source.symbols));
+        println!("Program Length: {:?}", vm.program.len());
+    }
+    let symbols = |kind| vm.source.symbols.iter()
+        .filter(|symbol| symbol.kind == kind)
+        .map(|symbol| format!("{} = {}", symbol.name, symbol.value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    for (title, kind) in [("Labels", SymbolKind::Label), ("Constants", SymbolKind::Constant)] {
+        let list = symbols(kind);
+        if !list.is_empty() {
+            println!("{}: {}", title, list);
+        }
+    }
     println!("Execution time: {:?}", elapsed);
 }
 
-pub fn parse_args() -> Vec<String> {
-    std::env::args().collect()
+// What to do with the source file: `e8bit FILE` is the same as `e8bit run FILE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Asm,
+    Disasm,
+    Link,
+    Fmt,
+    Lint,
+    Cfg,
+    Lsp,
+}
+
+pub struct Args {
+    pub command: Command,
+    pub file: Option<String>,
+    pub debug: bool,
+    pub key_queue: usize,
+    pub key_overflow: OverflowPolicy,
+    pub key_matrix: bool,
+    pub key_hold: u64,
+    pub fps: u8,
+    pub von_neumann: bool,
+    pub load_addr: usize,
+    pub include_dirs: Vec<String>,
+    pub defines: Vec<String>,
+    pub listing: Option<String>,
+    pub output: Option<String>,
+    // `asm -c`: write an object file for `link`
+    pub object: bool,
+    // the object files given to `link`, or the sources given to `fmt`
+    pub inputs: Vec<String>,
+    // `fmt --check`: only report files that are not formatted
+    pub check: bool,
+}
+
+pub const USAGE: &str = "\
+usage: e8bit [run] [FILE] [options]
+       e8bit asm|disasm|lint|cfg FILE [options]
+       e8bit link OBJECT... [-o FILE]
+       e8bit fmt [--check] FILE...
+       e8bit lsp [options]
+The options are described in README.md.
+";
+
+// The command line; a message on a wrong or missing option.
+pub fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        command: Command::Run,
+        file: None,
+        debug: false,
+        key_queue: Keyboard::DEFAULT_CAPACITY,
+        key_overflow: OverflowPolicy::DropNewest,
+        key_matrix: false,
+        key_hold: 500,
+        fps: 10,
+        von_neumann: false,
+        load_addr: 0,
+        include_dirs: Vec::new(),
+        defines: Vec::new(),
+        listing: None,
+        output: None,
+        object: false,
+        inputs: Vec::new(),
+        check: false,
+    };
+    let mut iter = std::env::args().skip(1).peekable();
+    match iter.peek().map(String::as_str) {
+        Some("run") => { iter.next(); }
+        Some("asm") => { iter.next(); args.command = Command::Asm; }
+        Some("disasm") => { iter.next(); args.command = Command::Disasm; }
+        Some("link") => { iter.next(); args.command = Command::Link; }
+        Some("fmt") => { iter.next(); args.command = Command::Fmt; }
+        Some("lint") => { iter.next(); args.command = Command::Lint; }
+        Some("cfg") => { iter.next(); args.command = Command::Cfg; }
+        Some("lsp") => { iter.next(); args.command = Command::Lsp; }
+        _ => {}
+    }
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-d" => args.debug = true,
+            "--key-queue" => {
+                args.key_queue = iter.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or("--key-queue expects a positive number")?;
+            }
+            "--key-overflow" => {
+                args.key_overflow = iter.next()
+                    .and_then(|p| OverflowPolicy::parse(&p))
+                    .ok_or("--key-overflow expects drop-newest or drop-oldest")?;
+            }
+            "--key-matrix" => args.key_matrix = true,
+            "--key-hold" => {
+                args.key_hold = iter.next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or("--key-hold expects a number of milliseconds")?;
+            }
+            "--fps" => {
+                args.fps = iter.next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or("--fps expects a number between 0 and 255")?;
+            }
+            "--von-neumann" => args.von_neumann = true,
+            "--load-addr" => {
+                args.load_addr = iter.next()
+                    .and_then(|n| n.parse::<u8>().ok())
+                    .ok_or("--load-addr expects an address between 0 and 255")? as usize;
+            }
+            "-I" => {
+                args.include_dirs.push(iter.next().ok_or("-I expects a directory")?);
+            }
+            "-D" => {
+                args.defines.push(iter.next().ok_or("-D expects NAME or NAME=value")?);
+            }
+            "-o" => {
+                args.output = Some(iter.next().ok_or("-o expects a file name")?);
+            }
+            "-c" => args.object = true,
+            "--check" => args.check = true,
+            "--listing" => {
+                args.listing = Some(iter.next().ok_or("--listing expects a file name")?);
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ if matches!(args.command, Command::Link | Command::Fmt) => args.inputs.push(arg),
+            _ => args.file = Some(arg),
+        }
+    }
+    if args.command == Command::Link && args.inputs.is_empty() {
+        return Err("link expects object files".to_string());
+    }
+    if args.command == Command::Fmt && args.inputs.is_empty() {
+        return Err("fmt expects source files".to_string());
+    }
+    if args.command != Command::Fmt && args.check {
+        return Err("--check only works with fmt".to_string());
+    }
+    if matches!(args.command, Command::Link | Command::Fmt | Command::Lsp) {
+        return Ok(args);
+    }
+    if args.command != Command::Run && args.file.is_none() {
+        return Err("asm, disasm, lint and cfg expect a file".to_string());
+    }
+    if args.command != Command::Asm && (args.listing.is_some() || args.object) {
+        return Err("--listing and -c only work with asm".to_string());
+    }
+    if matches!(args.command, Command::Run | Command::Lint) && args.output.is_some() {
+        return Err("-o only works with asm, disasm, link and cfg".to_string());
+    }
+    Ok(args)
 }
 
 pub fn clear_terminal_screen() {
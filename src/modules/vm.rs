@@ -1,8 +1,10 @@
-use std::time::Duration;
+use std::fmt;
+use std::time::{ Duration, Instant };
 use std::io::{ stdout, Write };
 use crate::modules::utils::{ clear_terminal_screen, simple_rand };
-use crossterm::event::{ poll, read, Event, KeyEvent, KeyCode };
-use crossterm::terminal::{ enable_raw_mode, disable_raw_mode };
+use crate::modules::keyboard::{ Keyboard, KEY_MATRIX_ADDR, KEY_MATRIX_SIZE };
+use crate::modules::bytecode::{ decode_instruction, encode_program, instruction_addresses };
+use crate::modules::program::{ DataBlock, SourceInfo };
 
 #[derive(Debug)]
 pub struct Vm {
@@ -10,11 +12,21 @@ pub struct Vm {
     pub reg: [u8; 5],
     pub mem: [u8; 256],
     pub program: Vec<Instruction>,
+    pub von_neumann: bool,
+    // where each instruction was written, for runtime errors
+    pub source: SourceInfo,
+    // von Neumann mode: the address of each instruction in `mem`
+    code_addresses: Vec<usize>,
     pub zf: bool,
     pub screen: [[char; 80]; 25],
+    pub key_mods: u8,
+    pub keyboard: Keyboard,
+    pub frame_interval: Duration,
+    pub frames: u64,
+    next_frame: Option<Instant>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reg {
     A,
     B,
@@ -23,6 +35,7 @@ pub enum Reg {
     E
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     MOV(Reg, Source),
@@ -41,6 +54,8 @@ pub enum Instruction {
     PRINTCH(Reg, bool),
     INPUT(Reg),
     INKEY(Reg),
+    KEYMOD(Reg),
+    KEYCOUNT(Reg),
     DRAW(Source, Source, Source),
     SLP(usize),
     CMP(Reg, Source),
@@ -48,9 +63,32 @@ pub enum Instruction {
     CLS,
     CTS,
     RENDER,
+    FRAME,
+    FPS(u8),
+    FCOUNT(Reg),
     HALT
 }
 
+impl Instruction {
+    pub fn jump_target(&self) -> Option<usize> {
+        match *self {
+            Instruction::JMP(addr) | Instruction::JZ(addr) | Instruction::JNZ(addr)
+            | Instruction::LOOP(addr, _) => Some(addr),
+            _ => None,
+        }
+    }
+
+    pub fn with_jump_target(self, target: usize) -> Self {
+        match self {
+            Instruction::JMP(_) => Instruction::JMP(target),
+            Instruction::JZ(_) => Instruction::JZ(target),
+            Instruction::JNZ(_) => Instruction::JNZ(target),
+            Instruction::LOOP(_, reg) => Instruction::LOOP(target, reg),
+            other => other,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Source {
     Reg(Reg),
@@ -64,6 +102,76 @@ pub enum MemSrc {
     Addr(u8)
 }
 
+// Instructions print as assembly source, with numbers for literals and
+// instruction indices for jump targets.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let newline = |newline: bool| if newline { "" } else { " -N" };
+        match self {
+            Instruction::MOV(reg, src) => write!(f, "MOV {} {}", reg, src),
+            Instruction::STORE(reg, mem) => write!(f, "STORE {} {}", reg, mem),
+            Instruction::ADD(reg, src) => write!(f, "ADD {} {}", reg, src),
+            Instruction::SUB(reg, src) => write!(f, "SUB {} {}", reg, src),
+            Instruction::MUL(reg, src) => write!(f, "MUL {} {}", reg, src),
+            Instruction::MULH(dest, src1, src2) => write!(f, "MULH {} {} {}", dest, src1, src2),
+            Instruction::DIV(reg, src) => write!(f, "DIV {} {}", reg, src),
+            Instruction::MOD(reg, src) => write!(f, "MOD {} {}", reg, src),
+            Instruction::JMP(addr) => write!(f, "JMP {}", addr),
+            Instruction::JZ(addr) => write!(f, "JZ {}", addr),
+            Instruction::JNZ(addr) => write!(f, "JNZ {}", addr),
+            Instruction::LOOP(addr, reg) => write!(f, "LOOP {} {}", addr, reg),
+            Instruction::PRINT(reg, opt) => write!(f, "PRINT {}{}", reg, newline(*opt)),
+            Instruction::PRINTCH(reg, opt) => write!(f, "PRINTCH {}{}", reg, newline(*opt)),
+            Instruction::INPUT(reg) => write!(f, "INPUT {}", reg),
+            Instruction::INKEY(reg) => write!(f, "INKEY {}", reg),
+            Instruction::KEYMOD(reg) => write!(f, "KEYMOD {}", reg),
+            Instruction::KEYCOUNT(reg) => write!(f, "KEYCOUNT {}", reg),
+            Instruction::DRAW(x, y, src) => write!(f, "DRAW {} {} {}", x, y, src),
+            Instruction::SLP(duration) => write!(f, "SLP {}", duration),
+            Instruction::CMP(reg, src) => write!(f, "CMP {} {}", reg, src),
+            Instruction::RAND(reg) => write!(f, "RAND {}", reg),
+            Instruction::CLS => write!(f, "CLS"),
+            Instruction::CTS => write!(f, "CTS"),
+            Instruction::RENDER => write!(f, "RENDER"),
+            Instruction::FRAME => write!(f, "FRAME"),
+            Instruction::FPS(rate) => write!(f, "FPS {}", rate),
+            Instruction::FCOUNT(reg) => write!(f, "FCOUNT {}", reg),
+            Instruction::HALT => write!(f, "HALT"),
+        }
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Reg(reg) => write!(f, "{}", reg),
+            Source::Mem(mem) => write!(f, "{}", mem),
+            Source::Lit(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl fmt::Display for MemSrc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemSrc::Reg(reg) => write!(f, "[{}]", reg),
+            MemSrc::Addr(addr) => write!(f, "[{}]", addr),
+        }
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Vm {
     pub fn new() -> Self {
         Vm {
@@ -71,8 +179,16 @@ impl Vm {
             reg: [0; 5],
             mem: [0; 256],
             program: Vec::new(),
+            von_neumann: false,
+            source: SourceInfo::default(),
+            code_addresses: Vec::new(),
             zf: false,
             screen: [[' '; 80]; 25],
+            key_mods: 0,
+            keyboard: Keyboard::default(),
+            frame_interval: Duration::from_millis(100),
+            frames: 0,
+            next_frame: None,
         }
     }
 
@@ -80,6 +196,103 @@ impl Vm {
         self.program = program;
     }
 
+    // Data may not overwrite the program in von Neumann mode, nor be
+    // overwritten by the key matrix.
+    pub fn load_data(&mut self, data: &[DataBlock]) -> Result<(), String> {
+        for block in data {
+            let start = block.addr as usize;
+            let end = start + block.bytes.len();
+            if self.von_neumann {
+                let code = self.code_addresses[0]..*self.code_addresses.last().unwrap();
+                if start < code.end && code.start < end {
+                    return Err(self.data_overlap(start.max(code.start), &format!(
+                        "the program, which is loaded at addresses {} to {}",
+                        code.start, code.end - 1,
+                    )));
+                }
+            }
+            if self.keyboard.has_matrix() && end > KEY_MATRIX_ADDR {
+                return Err(self.data_overlap(start.max(KEY_MATRIX_ADDR), &format!(
+                    "the key matrix at addresses {} to {} (--key-matrix)",
+                    KEY_MATRIX_ADDR, KEY_MATRIX_ADDR + KEY_MATRIX_SIZE - 1,
+                )));
+            }
+            self.mem[start..end].copy_from_slice(&block.bytes);
+        }
+        Ok(())
+    }
+
+    fn data_overlap(&self, addr: usize, what: &str) -> String {
+        let from = self.source.data_location(addr).map(|location| format!(" (from {})", location)).unwrap_or_default();
+        format!("Data at address {}{} overlaps {}", addr, from, what)
+    }
+
+    // Von Neumann mode: the program is encoded into `mem` at `origin` and
+    // fetched from there, so pc is a byte address.
+    pub fn load_program_into_memory(&mut self, program: &[Instruction], origin: usize) -> Result<(), String> {
+        let code = encode_program(program, origin)?;
+        if origin + code.len() > self.mem.len() {
+            return Err(format!(
+                "Program needs {} bytes at address {}, but memory ends at {}",
+                code.len(), origin, self.mem.len()
+            ));
+        }
+        // the matrix is copied into memory before every instruction
+        if self.keyboard.has_matrix() && origin + code.len() > KEY_MATRIX_ADDR {
+            return Err(format!(
+                "Program is loaded at addresses {} to {}, which overlaps the key matrix at addresses {} to {} (--key-matrix)",
+                origin, origin + code.len() - 1, KEY_MATRIX_ADDR, KEY_MATRIX_ADDR + KEY_MATRIX_SIZE - 1
+            ));
+        }
+        self.mem[origin..origin + code.len()].copy_from_slice(&code);
+        self.program = Vec::new();
+        self.code_addresses = instruction_addresses(program, origin);
+        self.von_neumann = true;
+        self.pc = origin as u16;
+        Ok(())
+    }
+
+    fn fetch(&mut self) -> Option<(Instruction, u16)> {
+        let pc = self.pc as usize;
+        if self.von_neumann {
+            if pc >= self.mem.len() {
+                return None;
+            }
+            // code overwritten by the program itself or by its data
+            let (instruction, size) = decode_instruction(&self.mem[pc..]).unwrap_or_else(|e| self.fault(&e));
+            Some((instruction, size as u16))
+        } else {
+            self.program.get(pc).map(|instruction| (*instruction, 1))
+        }
+    }
+
+    // Starts the program at instruction `index` instead of the first one.
+    pub fn start_at(&mut self, index: usize) {
+        self.pc = if self.von_neumann { self.code_addresses[index] } else { index } as u16;
+    }
+
+    // Index of the instruction at pc, if pc is at one.
+    pub fn current_instruction(&self) -> Option<usize> {
+        let pc = self.pc as usize;
+        if self.von_neumann {
+            self.code_addresses[..self.code_addresses.len().saturating_sub(1)].iter().position(|&addr| addr == pc)
+        } else {
+            (pc < self.program.len()).then_some(pc)
+        }
+    }
+
+    // Stops the program with a runtime error at the current instruction.
+    fn fault(&mut self, message: &str) -> ! {
+        let at = match self.current_instruction() {
+            Some(index) => self.source.describe(index),
+            None => format!("address {}", self.pc),
+        };
+        // out of raw mode before printing
+        self.keyboard.stop();
+        eprintln!("{} at {}", message, at);
+        std::process::exit(1);
+    }
+
     pub fn reg_index(&self, reg: Reg) -> usize {
         match reg {
             Reg::A => 0,
@@ -91,9 +304,18 @@ impl Vm {
     }
 
     pub fn run(&mut self) {
-        while self.pc < self.program.len() as u16 {
-            let instruction = &self.program[self.pc as usize];
-            match instruction {
+        if self.keyboard.has_matrix() {
+            self.keyboard.start();
+        }
+        loop {
+            if self.keyboard.has_matrix() {
+                self.mem[KEY_MATRIX_ADDR..KEY_MATRIX_ADDR + KEY_MATRIX_SIZE]
+                    .copy_from_slice(&self.keyboard.matrix());
+            }
+            let Some((instruction, size)) = self.fetch() else {
+                break;
+            };
+            match &instruction {
                 Instruction::MOV(reg, src) => self.mov(*reg, *src),
                 Instruction::STORE(reg, mem_src) => self.store(*reg, *mem_src),
                 Instruction::ADD(reg, src) => self.add(*reg, *src),
@@ -103,13 +325,15 @@ impl Vm {
                 Instruction::DIV(reg, src) => self.div(*reg, *src),
                 Instruction::MOD(reg, src) => self.mod_fn(*reg, *src),
                 Instruction::JMP(addr) => { self.jmp(*addr); continue; },
-                Instruction::JZ(addr) => { self.jz(*addr); continue; },
-                Instruction::JNZ(addr) => { self.jnz(*addr); continue; },
-                Instruction::LOOP(addr, reg) => { self.loop_fn(*addr, *reg); continue; },
+                Instruction::JZ(addr) => { self.jz(*addr, size); continue; },
+                Instruction::JNZ(addr) => { self.jnz(*addr, size); continue; },
+                Instruction::LOOP(addr, reg) => { self.loop_fn(*addr, *reg, size); continue; },
                 Instruction::PRINT(reg, opt) => self.print(*reg, *opt),
                 Instruction::PRINTCH(reg, opt) => self.printch(*reg, *opt),
                 Instruction::INPUT(reg) => self.input(*reg),
                 Instruction::INKEY(reg) => self.inkey(*reg),
+                Instruction::KEYMOD(reg) => self.keymod(*reg),
+                Instruction::KEYCOUNT(reg) => self.keycount(*reg),
                 Instruction::DRAW(x, y, src) => self.draw(*x, *y, *src),
                 Instruction::SLP(dur) => self.sleep(*dur),
                 Instruction::CMP(reg, src) => self.cmp(*reg, *src),
@@ -117,13 +341,17 @@ impl Vm {
                 Instruction::CLS => self.cls(),
                 Instruction::CTS => self.cts(),
                 Instruction::RENDER => self.render_screen(),
+                Instruction::FRAME => self.frame(),
+                Instruction::FPS(rate) => self.set_fps(*rate),
+                Instruction::FCOUNT(reg) => self.fcount(*reg),
                 Instruction::HALT => {
-                    println!("\n!-!- HALT !-!\n");
+                    print!("\r\n!-!- HALT !-!\r\n\r\n");
                     break;
                 }
             }
-            self.pc += 1;
+            self.pc += size;
         }
+        self.keyboard.stop();
     }
 
     fn mov(&mut self, reg: Reg, src: Source) {
@@ -238,7 +466,7 @@ impl Vm {
         match src {
             Source::Reg(src_reg) => {
                 if self.reg[self.reg_index(src_reg)] == 0 {
-                    panic!("Division by zero");
+                    self.fault("Division by zero");
                 }
                 let src_index = self.reg_index(src_reg);
                 let r = self.reg[self.reg_index(reg)].wrapping_div(self.reg[src_index]);
@@ -253,7 +481,7 @@ impl Vm {
                         MemSrc::Addr(addr) => addr as usize,
                     }
                 ] == 0 {
-                    panic!("Division by zero");
+                    self.fault("Division by zero");
                 }
                 let v = self.mem[
                     match value {
@@ -268,7 +496,7 @@ impl Vm {
             }
             Source::Lit(value) => {
                 if value == 0 {
-                    panic!("Division by zero");
+                    self.fault("Division by zero");
                 }
                 let v = value;
                 let r = self.reg[self.reg_index(reg)].wrapping_div(v);
@@ -285,7 +513,7 @@ impl Vm {
         match src {
             Source::Reg(src_reg) => {
                 if self.reg[self.reg_index(src_reg)] == 0 {
-                    panic!("Division by zero");
+                    self.fault("Division by zero");
                 }
                 let src_index = self.reg_index(src_reg);
                 let r = self.reg[self.reg_index(reg)].wrapping_rem(self.reg[src_index]);
@@ -300,7 +528,7 @@ impl Vm {
                         MemSrc::Addr(addr) => addr as usize,
                     }
                 ] == 0 {
-                    panic!("Division by zero");
+                    self.fault("Division by zero");
                 }
                 let v = self.mem[
                     match value {
@@ -315,7 +543,7 @@ impl Vm {
             }
             Source::Lit(value) => {
                 if value == 0 {
-                    panic!("Division by zero");
+                    self.fault("Division by zero");
                 }
                 let v = value;
                 let r = self.reg[self.reg_index(reg)].wrapping_rem(v);
@@ -374,30 +602,30 @@ impl Vm {
         // println!("JMP {:?}", addr);
     }
 
-    fn jz(&mut self, addr: usize) {
+    fn jz(&mut self, addr: usize, size: u16) {
         if self.zf {
             self.pc = addr as u16;
         } else {
-            self.pc += 1;
+            self.pc += size;
         }
         // println!("JZ {:?}", addr);
     }
 
-    fn jnz(&mut self, addr: usize) {
+    fn jnz(&mut self, addr: usize, size: u16) {
         if !self.zf {
             self.pc = addr as u16;
         } else {
-            self.pc += 1;
+            self.pc += size;
         }
         // println!("JNZ {:?}", addr);
     }
 
-    fn loop_fn(&mut self, addr: usize, reg: Reg) {
+    fn loop_fn(&mut self, addr: usize, reg: Reg, size: u16) {
         let index = self.reg_index(reg);
         if self.reg[index] > 0 {
             self.pc = addr as u16; // Jump if not zero
         } else {
-            self.pc += 1; // End loop
+            self.pc += size; // End loop
         }
         // println!("LOOP {:?} {:?}", addr, reg);
     }
@@ -405,7 +633,7 @@ impl Vm {
     fn print(&mut self, reg: Reg, opt: bool) {
         let val = self.reg[self.reg_index(reg)];
         if opt {
-            println!("{}", val);
+            print!("{}\r\n", val);
         } else {
             print!("{}", val);
             let _ = stdout().flush();
@@ -415,7 +643,7 @@ impl Vm {
     fn printch(&mut self, reg: Reg, opt: bool) {
         let val = self.reg[self.reg_index(reg)];
         if opt {
-            println!("{}", val as char);
+            print!("{}\r\n", val as char);
         } else {
             print!("{}", val as char);
             let _ = stdout().flush();
@@ -424,11 +652,13 @@ impl Vm {
 
     fn input(&mut self, reg: Reg) {
         let mut input = String::new();
+        self.keyboard.suspend();
         print!("INPUT {:?}: ", reg);
         let _ = stdout().flush();
         std::io::stdin()
             .read_line(&mut input)
             .expect("Failed to read line");
+        self.keyboard.resume();
     
         let trimmed = input.trim();
     
@@ -436,38 +666,37 @@ impl Vm {
             num
         } else if trimmed.len() == 1 {
             trimmed.chars().next().unwrap() as u8
-        } else if trimmed.len() == 0 {
+        } else if trimmed.is_empty() {
             self.zf = true;
             0
         } else {
-            panic!("Invalid input: expected a number or single character");
+            self.fault("Invalid input: expected a number or single character");
         };
     
         self.reg[self.reg_index(reg)] = value;
     }
 
     fn inkey(&mut self, reg: Reg) {
-        enable_raw_mode().unwrap();
-        use std::time::Duration;
-        let mut value = 0u8;
-        if poll(Duration::from_millis(0)).unwrap() {
-            if let Event::Key(KeyEvent { code, .. }) = read().unwrap() {
-                match code {
-                    KeyCode::Char(c) => value = c as u8,
-                    KeyCode::Enter => value = b'\n',
-                    KeyCode::Tab => value = b'\t',
-                    KeyCode::Backspace => value = 8,
-                    KeyCode::Esc => value = 27,
-                    _ => value = 0,
-                }
-            }
-            while poll(Duration::from_millis(0)).unwrap() {
-                let _ = read();
-            }
-        }
+        self.keyboard.start();
+        let (value, mods) = match self.keyboard.pop() {
+            Some(key) => (key.code, key.mods),
+            None => (0, 0),
+        };
         self.zf = value == 0;
         self.reg[self.reg_index(reg)] = value;
-        disable_raw_mode().unwrap();
+        self.key_mods = mods;
+    }
+
+    fn keymod(&mut self, reg: Reg) {
+        self.zf = self.key_mods == 0;
+        self.reg[self.reg_index(reg)] = self.key_mods;
+    }
+
+    fn keycount(&mut self, reg: Reg) {
+        self.keyboard.start();
+        let count = self.keyboard.pending().min(255) as u8;
+        self.zf = count == 0;
+        self.reg[self.reg_index(reg)] = count;
     }
 
     fn draw(&mut self, x: Source, y: Source, src: Source) {
@@ -518,15 +747,45 @@ impl Vm {
     }
     
     fn render_screen(&self) {
-        println!("{}", format!("+{}+", "-".repeat(80)));
+        // explicit \r so the frame stays aligned while INKEY holds raw mode
+        print!("+{}+\r\n", "-".repeat(80));
         for row in self.screen.iter() {
             print!("|");
             for &ch in row.iter() {
                 print!("{}", ch);
             }
-            println!("|");
+            print!("|\r\n");
+        }
+        print!("+{}+\r\n", "-".repeat(80));
+    }
+
+    fn frame(&mut self) {
+        self.render_screen();
+        self.frames += 1;
+        let now = Instant::now();
+        let target = self.next_frame.unwrap_or(now + self.frame_interval);
+        if target > now {
+            std::thread::sleep(target - now);
+            self.next_frame = Some(target + self.frame_interval);
+        } else {
+            // running behind: start counting again instead of rushing frames
+            self.next_frame = Some(now + self.frame_interval);
         }
-        println!("{}", format!("+{}+", "-".repeat(80)));
+    }
+
+    pub fn set_fps(&mut self, rate: u8) {
+        self.frame_interval = if rate == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(1) / rate as u32
+        };
+        self.next_frame = None;
+    }
+
+    fn fcount(&mut self, reg: Reg) {
+        let count = self.frames as u8;
+        self.zf = count == 0;
+        self.reg[self.reg_index(reg)] = count;
     }
 
     fn sleep(&self, duration: usize) {